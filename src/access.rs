@@ -0,0 +1,57 @@
+use std::io;
+use std::net::IpAddr;
+
+use crate::config;
+use crate::status::Status;
+use crate::util;
+
+pub fn valid_cidr(cidr: &str) -> bool {
+    let (net, len) = match cidr.split_once('/') {
+        Some((n, l)) => (n, Some(l)),
+        None => (cidr, None),
+    };
+    let max = match net.parse::<IpAddr>() {
+        Ok(IpAddr::V4(_)) => 32,
+        Ok(IpAddr::V6(_)) => 128,
+        Err(_) => return false,
+    };
+    match len {
+        Some(l) => l.parse::<u32>().is_ok_and(|l| l <= max),
+        None => true,
+    }
+}
+
+pub fn validate(a: &config::Access) -> io::Result<()> {
+    let ranges = a.allow.iter().flatten().chain(a.deny.iter().flatten());
+    for r in ranges {
+        if !valid_cidr(r) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("access has invalid address range {}", r),
+            ));
+        }
+    }
+    if let Some(s) = a.status {
+        if Status::from_u8(s).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("access has unknown status {}", s),
+            ));
+        }
+    }
+    Ok(())
+}
+
+// deny wins over allow. If allow is set only the addresses in it get in.
+// A client without an address is in no range, so it's kept out by allow but
+// not by deny.
+pub fn allowed(a: &config::Access, ip: Option<IpAddr>) -> bool {
+    let within = |r: &String| ip.is_some_and(|ip| util::ip_in(ip, r));
+    if a.deny.iter().flatten().any(within) {
+        return false;
+    }
+    match &a.allow {
+        Some(allow) => allow.iter().any(within),
+        None => true,
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config;
+use crate::conn::Connection;
+use crate::logfile::{Files, LogFile};
+use crate::util;
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+#[derive(Debug, Clone, Copy)]
+pub enum Format {
+    Logfmt,
+    Json,
+    Clf,
+}
+
+impl Format {
+    pub fn parse(f: Option<&str>) -> io::Result<Format> {
+        match f.unwrap_or("logfmt") {
+            "logfmt" => Ok(Format::Logfmt),
+            "json" => Ok(Format::Json),
+            "clf" => Ok(Format::Clf),
+            f => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("access_log has unknown format {}", f),
+            )),
+        }
+    }
+}
+
+// Hands out request IDs. They start with the startup time so they don't repeat
+// across restarts.
+pub struct Ids {
+    start: u64,
+    next: AtomicU64,
+}
+
+impl Ids {
+    pub fn new() -> Ids {
+        let start = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Ids {
+            start,
+            next: AtomicU64::new(0),
+        }
+    }
+
+    pub fn next(&self) -> String {
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        format!("{:x}-{:06x}", self.start, n)
+    }
+}
+
+pub struct AccessLog {
+    format: Format,
+    // stdout if None
+    out: Option<Arc<LogFile>>,
+}
+
+impl AccessLog {
+    pub fn new(cfg: &config::AccessLog, files: &mut Files) -> io::Result<AccessLog> {
+        Ok(AccessLog {
+            format: Format::parse(cfg.format.as_deref())?,
+            out: files.open(&cfg.output)?,
+        })
+    }
+
+    // Write the record for a finished request. took is how long it took from
+    // accepting the connection.
+    pub fn write(&self, con: &Connection, took: Duration) {
+        let mut line = format_record(self.format, con, took, SystemTime::now());
+        match &self.out {
+            Some(f) => f.write(&line),
+            None => {
+                line.push('\n');
+                let _ = io::stdout().lock().write_all(line.as_bytes());
+            }
+        }
+    }
+}
+
+// The server wide access log and the ones vhosts have of their own
+pub struct AccessLogs {
+    global: Option<AccessLog>,
+    vhosts: HashMap<String, AccessLog>,
+}
+
+impl AccessLogs {
+    pub fn new(cfg: &config::Config, files: &mut Files) -> io::Result<AccessLogs> {
+        let global = cfg.access_log.as_ref().map(|a| AccessLog::new(a, files)).transpose()?;
+        let mut vhosts = HashMap::new();
+        for srv in cfg.server.iter() {
+            if let Some(a) = &srv.access_log {
+                vhosts.insert(srv.hostname.clone(), AccessLog::new(a, files)?);
+            }
+        }
+        Ok(AccessLogs { global, vhosts })
+    }
+
+    // The log for requests to vhost
+    pub fn get(&self, vhost: &str) -> Option<&AccessLog> {
+        self.vhosts.get(vhost).or(self.global.as_ref())
+    }
+}
+
+fn rfc3339(t: SystemTime) -> String {
+    let (y, m, d, hh, mm, ss) = util::civil(t);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", y, m, d, hh, mm, ss)
+}
+
+fn clf_time(t: SystemTime) -> String {
+    let (y, m, d, hh, mm, ss) = util::civil(t);
+    format!(
+        "{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000",
+        d, MONTHS[(m - 1) as usize], y, hh, mm, ss
+    )
+}
+
+fn logfmt_value(v: &str) -> String {
+    if !v.is_empty() && !v.contains(|c: char| c == ' ' || c == '=' || c == '"' || c.is_control()) {
+        return v.to_string();
+    }
+    format!("{:?}", v)
+}
+
+fn json_string(v: &str) -> String {
+    let mut s = String::from("\"");
+    for c in v.chars() {
+        match c {
+            '"' => s.push_str("\\\""),
+            '\\' => s.push_str("\\\\"),
+            '\n' => s.push_str("\\n"),
+            '\r' => s.push_str("\\r"),
+            '\t' => s.push_str("\\t"),
+            c if c.is_control() => s.push_str(&format!("\\u{:04x}", c as u32)),
+            c => s.push(c),
+        }
+    }
+    s.push('"');
+    s
+}
+
+fn format_record(format: Format, con: &Connection, took: Duration, now: SystemTime) -> String {
+    let r = &con.record;
+    let status = r.status.map(|s| s.to_string());
+    let cert = con.peer.cert.as_ref().map(|c| c.hash.as_str());
+    let ms = took.as_millis().to_string();
+    let bytes = r.bytes.to_string();
+    let remote = con.peer.addr.to_string();
+    let fields: [(&str, Option<&str>); 12] = [
+        ("id", Some(&r.id)),
+        ("listener", Some(&r.listener)),
+        ("vhost", Some(&r.vhost)),
+        ("remote", Some(&remote)),
+        ("request", Some(&r.request)),
+        ("status", status.as_deref()),
+        ("meta", Some(&r.meta)),
+        ("bytes", Some(&bytes)),
+        ("duration_ms", Some(&ms)),
+        ("cert", cert),
+        ("handler", r.handler),
+        ("tls", con.peer.protocol.as_deref()),
+    ];
+    match format {
+        Format::Logfmt => {
+            let mut out = format!("time={}", rfc3339(now));
+            for (k, v) in fields.iter() {
+                out.push_str(&format!(" {}={}", k, logfmt_value(v.unwrap_or("-"))));
+            }
+            out
+        }
+        Format::Json => {
+            let mut out = format!("{{\"time\":\"{}\"", rfc3339(now));
+            for (k, v) in fields.iter() {
+                let v = match (*k, v) {
+                    (_, None) => "null".to_string(),
+                    ("status", Some(v)) | ("bytes", Some(v)) | ("duration_ms", Some(v)) => v.to_string(),
+                    (_, Some(v)) => json_string(v),
+                };
+                out.push_str(&format!(",\"{}\":{}", k, v));
+            }
+            out.push('}');
+            out
+        }
+        // The usual host, ident, user, time, request, status and size followed
+        // by meta, vhost, handler, duration, ID and listener
+        Format::Clf => format!(
+            "{} - {} [{}] {} {} {} {} {} {} {} {} {}",
+            con.peer.addr.ip(),
+            cert.unwrap_or("-"),
+            clf_time(now),
+            json_string(&r.request),
+            status.as_deref().unwrap_or("-"),
+            r.bytes,
+            json_string(&r.meta),
+            r.vhost,
+            r.handler.unwrap_or("-"),
+            ms,
+            r.id,
+            r.listener,
+        ),
+    }
+}
@@ -0,0 +1,75 @@
+use std::fs;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::ban::Bans;
+use crate::connlimit::Counter;
+use crate::util;
+
+// Answer one command. Commands are:
+//   bans             list active bans
+//   unban <ip>|all   lift one or every ban
+//   connections      open connections and the clients with the most
+fn command(line: &str, bans: Option<&Bans>, conns: &Counter) -> String {
+    let mut words = line.split_whitespace();
+    match (words.next(), words.next(), bans) {
+        (Some("connections"), None, _) => conns.stats(20),
+        (Some("bans"), None, Some(b)) => b
+            .list()
+            .iter()
+            .map(|(ip, until)| format!("{} until {}\n", ip, util::fmt_time(*until)))
+            .collect(),
+        (Some("unban"), Some("all"), Some(b)) => format!("lifted {}\n", b.unban(None)),
+        (Some("unban"), Some(ip), Some(b)) => match ip.parse::<IpAddr>() {
+            Ok(ip) => format!("lifted {}\n", b.unban(Some(ip))),
+            Err(_) => "error: bad address\n".to_string(),
+        },
+        (Some("bans"), _, None) | (Some("unban"), _, None) => "error: bans aren't enabled\n".to_string(),
+        _ => "error: unknown command\n".to_string(),
+    }
+}
+
+async fn client(stream: UnixStream, bans: Option<Arc<Bans>>, conns: Arc<Counter>) -> std::io::Result<()> {
+    let (read, mut write) = tokio::io::split(stream);
+    let mut lines = BufReader::new(read).lines();
+    while let Some(line) = lines.next_line().await? {
+        let reply = command(&line, bans.as_deref(), &conns);
+        write.write_all(reply.as_bytes()).await?;
+        write.write_all(b".\n").await?;
+    }
+    Ok(())
+}
+
+// Listen for admin commands on a Unix socket at path. Anyone who can open the
+// socket can use it, so it should be somewhere only the admin can reach.
+pub async fn serve(path: String, bans: Option<Arc<Bans>>, conns: Arc<Counter>) {
+    let _ = fs::remove_file(&path);
+    let mut listener = match UnixListener::bind(&path) {
+        Ok(l) => l,
+        Err(e) => {
+            log::error!("Can't listen on admin socket {}: {}", path, e);
+            return;
+        }
+    };
+    loop {
+        let stream = match listener.accept().await {
+            Ok((s, _)) => s,
+            Err(e) => {
+                log::error!("Admin socket: {}", e);
+                tokio::time::delay_for(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let bans = bans.clone();
+        let conns = conns.clone();
+        tokio::spawn(async move {
+            if let Err(e) = client(stream, bans, conns).await {
+                log::error!("Admin socket: {}", e);
+            }
+        });
+    }
+}
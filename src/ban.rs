@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config;
+use crate::util;
+
+const DEFAULT_MAX: usize = 10;
+const DEFAULT_WINDOW: u64 = 60;
+const DEFAULT_TIME: u64 = 600;
+// Forget about clients that stopped misbehaving once this many are tracked
+const MAX_TRACKED: usize = 10000;
+
+#[derive(Default)]
+struct Inner {
+    strikes: HashMap<IpAddr, Vec<Instant>>,
+    banned: HashMap<IpAddr, SystemTime>,
+}
+
+pub struct Bans {
+    cfg: config::Ban,
+    inner: Mutex<Inner>,
+}
+
+fn unix(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl Bans {
+    // Bans saved to the ban file by an earlier run are loaded if they
+    // haven't run out yet.
+    pub fn new(cfg: &config::Ban) -> Bans {
+        let mut inner = Inner::default();
+        if let Some(s) = cfg.file.as_ref().and_then(|f| fs::read_to_string(f).ok()) {
+            let now = SystemTime::now();
+            for line in s.lines() {
+                let mut parts = line.split_whitespace();
+                let ip = parts.next().and_then(|i| i.parse::<IpAddr>().ok());
+                let until = parts.next().and_then(|u| u.parse::<u64>().ok());
+                if let (Some(ip), Some(until)) = (ip, until) {
+                    let until = UNIX_EPOCH + Duration::from_secs(until);
+                    if until > now {
+                        inner.banned.insert(ip, until);
+                    }
+                }
+            }
+            log::info!("Loaded {} bans", inner.banned.len());
+        }
+        Bans {
+            cfg: cfg.clone(),
+            inner: Mutex::new(inner),
+        }
+    }
+
+    pub fn banned(&self, ip: IpAddr) -> bool {
+        let ip = util::canonical_ip(ip);
+        let mut inner = self.inner.lock().unwrap();
+        match inner.banned.get(&ip) {
+            Some(until) if *until > SystemTime::now() => true,
+            Some(_) => {
+                inner.banned.remove(&ip);
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub fn counts_status(&self, code: u8) -> bool {
+        match &self.cfg.statuses {
+            Some(s) => s.contains(&code),
+            None => code == 59,
+        }
+    }
+
+    pub fn counts_tls(&self) -> bool {
+        self.cfg.tls.unwrap_or(true)
+    }
+
+    // Count a bad outcome for ip and ban it once it has too many within the
+    // window. what is logged as the reason.
+    pub fn strike(&self, ip: IpAddr, what: &str) {
+        let ip = util::canonical_ip(ip);
+        if self.cfg.ignore.iter().flatten().any(|r| util::ip_in(ip, r)) {
+            return;
+        }
+        let window = Duration::from_secs(self.cfg.window.unwrap_or(DEFAULT_WINDOW));
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.strikes.contains_key(&ip) && inner.strikes.len() >= MAX_TRACKED {
+            inner.strikes.retain(|_, s| s.last().is_some_and(|t| now.duration_since(*t) < window));
+        }
+        let strikes = inner.strikes.entry(ip).or_default();
+        strikes.retain(|t| now.duration_since(*t) < window);
+        strikes.push(now);
+        if strikes.len() < self.cfg.max.unwrap_or(DEFAULT_MAX) {
+            return;
+        }
+        inner.strikes.remove(&ip);
+        let time = self.cfg.time.unwrap_or(DEFAULT_TIME);
+        inner.banned.insert(ip, SystemTime::now() + Duration::from_secs(time));
+        log::warn!("remote={} banned={}s reason={}", ip, time, what);
+        self.save(&inner);
+    }
+
+    // Every active ban with when it runs out
+    pub fn list(&self) -> Vec<(IpAddr, SystemTime)> {
+        let now = SystemTime::now();
+        let inner = self.inner.lock().unwrap();
+        let mut bans: Vec<_> = inner
+            .banned
+            .iter()
+            .filter(|(_, u)| **u > now)
+            .map(|(ip, u)| (*ip, *u))
+            .collect();
+        bans.sort();
+        bans
+    }
+
+    // Lift the ban on ip, or every ban if ip is None. Returns how many were
+    // lifted.
+    pub fn unban(&self, ip: Option<IpAddr>) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let n = match ip {
+            Some(ip) => {
+                let ip = util::canonical_ip(ip);
+                inner.strikes.remove(&ip);
+                inner.banned.remove(&ip).map_or(0, |_| 1)
+            }
+            None => {
+                inner.strikes.clear();
+                let n = inner.banned.len();
+                inner.banned.clear();
+                n
+            }
+        };
+        if n > 0 {
+            self.save(&inner);
+        }
+        n
+    }
+
+    fn save(&self, inner: &Inner) {
+        let file = match &self.cfg.file {
+            Some(f) => f,
+            None => return,
+        };
+        let mut s = String::new();
+        for (ip, until) in &inner.banned {
+            s.push_str(&format!("{} {}\n", ip, unix(*until)));
+        }
+        if let Err(e) = fs::write(file, s) {
+            log::error!("Can't write ban file {}: {}", file, e);
+        }
+    }
+}
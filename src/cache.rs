@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::config;
+
+// Files bigger than this are always streamed from disk.
+const DEFAULT_FILE_SIZE: u64 = 128 * 1024;
+const DEFAULT_SIZE: usize = 16 * 1024 * 1024;
+const DEFAULT_ENTRIES: usize = 1024;
+
+struct Entry {
+    mtime: SystemTime,
+    len: u64,
+    body: Arc<Vec<u8>>,
+    used: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    map: HashMap<String, Entry>,
+    size: usize,
+    tick: u64,
+}
+
+pub struct Cache {
+    max_size: usize,
+    max_entries: usize,
+    pub max_file_size: u64,
+    inner: Mutex<Inner>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl std::fmt::Debug for Cache {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Cache {{ {} }}", self.stats())
+    }
+}
+
+impl Cache {
+    pub fn new(cfg: &config::CacheCfg) -> Cache {
+        Cache {
+            max_size: cfg.size.unwrap_or(DEFAULT_SIZE),
+            max_entries: cfg.entries.unwrap_or(DEFAULT_ENTRIES),
+            max_file_size: cfg.file_size.unwrap_or(DEFAULT_FILE_SIZE),
+            inner: Mutex::new(Inner::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    // Look up key and make sure it's still as fresh as the file or directory
+    // described by meta. Stale entries are dropped.
+    pub fn get(&self, key: &str, meta: &Metadata) -> Option<Arc<Vec<u8>>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let fresh = match inner.map.get_mut(key) {
+            Some(e) if Some(e.mtime) == meta.modified().ok() && e.len == meta.len() => {
+                e.used = tick;
+                Some(e.body.clone())
+            }
+            Some(_) => None,
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        };
+        match fresh {
+            Some(body) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(body)
+            }
+            None => {
+                if let Some(e) = inner.map.remove(key) {
+                    inner.size -= e.body.len();
+                }
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    pub fn insert(&self, key: &str, meta: &Metadata, body: Vec<u8>) -> Arc<Vec<u8>> {
+        let body = Arc::new(body);
+        let mtime = match meta.modified() {
+            Ok(m) => m,
+            Err(_) => return body,
+        };
+        if body.len() > self.max_size {
+            return body;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(e) = inner.map.remove(key) {
+            inner.size -= e.body.len();
+        }
+        while !inner.map.is_empty()
+            && (inner.map.len() >= self.max_entries || inner.size + body.len() > self.max_size)
+        {
+            let oldest = match inner.map.iter().min_by_key(|(_, e)| e.used) {
+                Some((k, _)) => k.clone(),
+                None => break,
+            };
+            if let Some(e) = inner.map.remove(&oldest) {
+                inner.size -= e.body.len();
+            }
+        }
+        inner.tick += 1;
+        let used = inner.tick;
+        inner.size += body.len();
+        inner.map.insert(
+            key.to_string(),
+            Entry {
+                mtime,
+                len: meta.len(),
+                body: body.clone(),
+                used,
+            },
+        );
+        body
+    }
+
+    pub fn stats(&self) -> String {
+        let inner = self.inner.lock().unwrap();
+        format!(
+            "hits={} misses={} entries={} size={}",
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+            inner.map.len(),
+            inner.size
+        )
+    }
+}
@@ -19,42 +19,33 @@ use crate::config;
 use crate::conn;
 use crate::logger;
 use crate::status::Status;
-use crate::util;
 
 #[cfg(any(feature = "cgi", feature = "scgi"))]
-fn envs(peer_addr: SocketAddr, x509: Option<openssl::x509::X509>, srv: &config::ServerCfg, url: &url::Url) -> HashMap<String, String> {
+// url may have been rewritten, request is what the client asked for
+fn envs(peer: &conn::PeerInfo, srv: &config::ServerCfg, url: &url::Url, request: &str) -> HashMap<String, String> {
     let mut envs = HashMap::new();
     envs.insert("GATEWAY_INTERFACE".to_string(), "CGI/1.1".to_string());
-    envs.insert("GEMINI_URL".to_string(), url.to_string());
+    envs.insert("GEMINI_URL".to_string(), request.to_string());
     envs.insert("SERVER_NAME".to_string(), url.host_str().unwrap().to_string());
     envs.insert("SERVER_PROTOCOL".to_string(), "GEMINI".to_string());
-    let addr = peer_addr.ip().to_string();
-    envs.insert("REMOTE_ADDR".to_string(), addr.clone());
-    envs.insert("REMOTE_HOST".to_string(), addr);
-    let port = peer_addr.port().to_string();
-    envs.insert("REMOTE_PORT".to_string(), port);
+    // Left out for Unix socket clients with no address
+    if let Some(ip) = peer.ip() {
+        envs.insert("REMOTE_ADDR".to_string(), ip.to_string());
+        envs.insert("REMOTE_HOST".to_string(), ip.to_string());
+        envs.insert("REMOTE_PORT".to_string(), peer.addr.port().to_string());
+    }
     envs.insert("SERVER_SOFTWARE".to_string(), env!("CARGO_PKG_NAME").to_string());
 
     if let Some(q) = url.query() {
         envs.insert("QUERY_STRING".to_string(), q.to_string());
     }
 
-    match x509 {
-        Some(x) => {
-            envs.insert("AUTH_TYPE".to_string(), "Certificate".to_string());
-
-            let cn = x.subject_name().entries_by_nid(openssl::nid::Nid::COMMONNAME);
-            for c in cn {
-               let cd = match c.data().as_utf8() {
-                    Ok(n) => n.to_string(),
-                    _ => "".to_string(),
-                };
-               envs.insert("REMOTE_USER".to_string(), cd);
-            }
-
-            envs.insert("TLS_CLIENT_HASH".to_string(), util::fingerhex(&x));
-        },
-        None => {},
+    if let Some(c) = &peer.cert {
+        envs.insert("AUTH_TYPE".to_string(), "Certificate".to_string());
+        if let Some(n) = &c.name {
+            envs.insert("REMOTE_USER".to_string(), n.clone());
+        }
+        envs.insert("TLS_CLIENT_HASH".to_string(), c.hash.clone());
     }
 
     match &srv.server.cgienv {
@@ -92,12 +83,12 @@ pub async fn cgi(
     srv: &config::ServerCfg,
     path: PathBuf,
     url: &url::Url,
+    request: &str,
     script_name: String,
     path_info: String
 ) -> Result<(), io::Error> {
 
-    let x509 = con.stream.ssl().peer_certificate();
-    let mut envs = envs(con.peer_addr, x509, srv, &url);
+    let mut envs = envs(&con.peer, srv, &url, request);
     envs.insert("SCRIPT_NAME".into(), script_name);
     envs.insert("PATH_INFO".into(), path_info);
 
@@ -111,34 +102,36 @@ pub async fn cgi(
     let cmd = Command::new(path.to_str().unwrap())
         .env_clear()
         .envs(&envs)
+        .kill_on_drop(true)
         .output();
 
-    let cmd = match tokio::time::timeout(tokio::time::Duration::from_secs(5), cmd).await {
+    let cmd = match tokio::time::timeout(srv.timeouts(url.path()).handler(), cmd).await {
         Ok(c) => {
             match c {
                 Ok(cc) => cc,
 
                 Err(_) => {
-                    logger::logger(con.peer_addr, Status::CGIError, url.as_str());
+                    logger::logger(con.peer.addr, Status::CGIError, url.as_str());
                     con.send_status(Status::CGIError, None).await?;
                     return Ok(());
                 },
             }
         },
         Err(_) => {
-            logger::logger(con.peer_addr, Status::CGIError, url.as_str());
+            logger::timeout(con.peer.addr, "handler", url.as_str());
+            logger::logger(con.peer.addr, Status::CGIError, url.as_str());
             con.send_status(Status::CGIError, None).await?;
             return Ok(());
         },
     };
 
     if !cmd.status.success() {
-        logger::logger(con.peer_addr, Status::CGIError, url.as_str());
+        logger::logger(con.peer.addr, Status::CGIError, url.as_str());
         con.send_status(Status::CGIError, None).await?;
         return Ok(());
     }
     let cmd = cmd.stdout;
-    if !check(cmd[0], con.peer_addr, url) {
+    if !check(cmd[0], con.peer.addr, url) {
         con.send_status(Status::CGIError, None).await?;
         return Ok(());
     }
@@ -148,22 +141,28 @@ pub async fn cgi(
 }
 
 #[cfg(feature = "scgi")]
-pub async fn scgi(addr: String, u: url::Url, mut con: conn::Connection, srv: &config::ServerCfg) -> Result<(), io::Error> {
+pub async fn scgi(addr: String, u: url::Url, request: &str, con: &mut conn::Connection, srv: &config::ServerCfg) -> Result<(), io::Error> {
     let addr = addr
         .to_socket_addrs()?
         .next()
         .ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))?;
 
-    let mut stream = match TcpStream::connect(&addr).await {
-        Ok(s) => s,
+    let timeout = srv.timeouts(u.path()).handler();
+    let mut stream = match tokio::time::timeout(timeout, TcpStream::connect(&addr)).await {
+        Ok(Ok(s)) => s,
+        Ok(Err(_)) => {
+            logger::logger(con.peer.addr, Status::CGIError, u.as_str());
+            con.send_status(Status::CGIError, None).await?;
+            return Ok(());
+        }
         Err(_) => {
-            logger::logger(con.peer_addr, Status::CGIError, u.as_str());
+            logger::timeout(con.peer.addr, "handler", u.as_str());
+            logger::logger(con.peer.addr, Status::CGIError, u.as_str());
             con.send_status(Status::CGIError, None).await?;
             return Ok(());
         }
     };
-    let x509 = con.stream.ssl().peer_certificate();
-    let envs = envs(con.peer_addr, x509, srv, &u);
+    let envs = envs(&con.peer, srv, &u, request);
     let len = 0usize;
     let mut byt = String::from(format!("CONTENT_LENGTH\x00{}\x00SCGI\x001\x00
         RQUEST_METHOD\x00POST\x00REQUEST_URI\x00{}\x00", len, u.path()));
@@ -176,14 +175,14 @@ pub async fn scgi(addr: String, u: url::Url, mut con: conn::Connection, srv: &co
     stream.flush().await?;
 
     let mut buf = vec![];
-    if let Err(_) = tokio::time::timeout(
-        tokio::time::Duration::from_secs(5), stream.read_to_end(&mut buf)).await {
-        logger::logger(con.peer_addr, Status::CGIError, u.as_str());
+    if let Err(_) = tokio::time::timeout(timeout, stream.read_to_end(&mut buf)).await {
+        logger::timeout(con.peer.addr, "handler", u.as_str());
+        logger::logger(con.peer.addr, Status::CGIError, u.as_str());
         con.send_status(Status::CGIError, None).await?;
         return Ok(());
     }
     let req = String::from_utf8_lossy(&buf[..]);
-    if !check(req.as_bytes()[0], con.peer_addr, &u) {
+    if !check(req.as_bytes()[0], con.peer.addr, &u) {
         con.send_status(Status::CGIError, None).await?;
         return Ok(());
     }
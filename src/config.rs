@@ -1,25 +1,63 @@
 extern crate serde_derive;
 extern crate toml;
 use std::collections::HashMap;
+use std::io;
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 use toml::de::Error;
 
+use crate::cache::Cache;
+use crate::access;
+use crate::ratelimit::{self, Limiter, Throttle};
+use crate::redirect;
+use crate::rewrite;
+use crate::status::Status;
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
     pub port: u16,
     pub host: String,
     pub log: Option<String>,
+    pub cache: Option<CacheCfg>,
+    pub timeout: Option<Timeouts>,
+    pub ratelimit_entries: Option<usize>,
+    pub access: Option<Access>,
+    pub ban: Option<Ban>,
+    pub connections: Option<Connections>,
+    pub bandwidth: Option<Bandwidth>,
+    pub proxy_protocol: Option<ProxyProtocol>,
+    pub listen: Option<Vec<Listen>>,
+    pub admin: Option<String>,
+    pub access_log: Option<AccessLog>,
+    pub error_log: Option<LogOutput>,
     pub server: Vec<Server>,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct CacheCfg {
+    pub size: Option<usize>,
+    pub entries: Option<usize>,
+    pub file_size: Option<u64>,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Server {
     pub hostname: String,
-    pub dir: String,
+    pub alias: Option<Vec<String>>,
+    pub redirect_host: Option<String>,
+    pub dir: Option<String>,
     pub key: String,
     pub cert: String,
-    pub index: Option<String>,
+    pub index: Option<Index>,
+    pub lang_hint: Option<bool>,
     pub lang: Option<String>,
+    pub mime: Option<HashMap<String, String>>,
+    pub mime_sniff: Option<bool>,
+    pub mime_default: Option<String>,
+    pub charset: Option<String>,
+    pub charsets: Option<HashMap<String, String>>,
+    pub charset_detect: Option<bool>,
     #[cfg(feature = "cgi")]
     pub cgi: Option<bool>,
     #[cfg(feature = "cgi")]
@@ -27,19 +65,337 @@ pub struct Server {
     #[cfg(any(feature = "cgi", feature = "scgi"))]
     pub cgienv: Option<HashMap<String, String>>,
     pub usrdir: Option<bool>,
+    pub clean_urls: Option<bool>,
+    pub clean_redirect: Option<bool>,
+    pub dirmeta: Option<bool>,
+    pub dirlist: Option<DirList>,
+    pub dirlists: Option<HashMap<String, DirList>>,
     #[cfg(feature = "proxy")]
     pub proxy: Option<HashMap<String, String>>,
     #[cfg(feature = "proxy")]
     pub proxy_all: Option<String>,
+    pub proxy_header: Option<u8>,
     pub redirect: Option<HashMap<String, String>>,
+    pub redirect_rules: Option<Vec<RedirectRule>>,
+    pub rewrite: Option<Vec<RewriteRule>>,
+    pub gone: Option<HashMap<String, String>>,
+    pub response: Option<Vec<Response>>,
+    pub errors: Option<Errors>,
+    pub maintenance: Option<Maintenance>,
+    pub timeout: Option<Timeouts>,
+    pub timeouts: Option<HashMap<String, Timeouts>>,
+    pub ratelimit: Option<RateLimit>,
+    pub ratelimits: Option<HashMap<String, RateLimit>>,
+    pub access: Option<Access>,
+    pub accesses: Option<HashMap<String, Access>>,
+    pub bandwidth: Option<Bandwidth>,
+    pub access_log: Option<AccessLog>,
+    pub error_log: Option<LogOutput>,
+    pub gone_files: Option<bool>,
     #[cfg(feature = "scgi")]
     pub scgi: Option<HashMap<String, String>>,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct RedirectRule {
+    pub regex: Option<String>,
+    pub glob: Option<String>,
+    pub to: String,
+    pub permanent: Option<bool>,
+    pub query: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RewriteRule {
+    pub regex: Option<String>,
+    pub glob: Option<String>,
+    pub query: Option<String>,
+    pub cert: Option<bool>,
+    pub to: String,
+}
+
+// A fixed response for a path. body and file are only sent with 2x statuses.
+// forward is where a request with a query goes instead, e.g. the answer to
+// a 10 prompt.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Response {
+    pub path: String,
+    pub status: u8,
+    pub meta: Option<String>,
+    pub body: Option<String>,
+    pub file: Option<String>,
+    pub forward: Option<String>,
+}
+
+// Replacement meta text for error statuses keyed by status code, optionally
+// per language. pages are gemtext files sent with 20 instead of the error.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Errors {
+    pub meta: Option<HashMap<String, String>>,
+    pub lang: Option<HashMap<String, HashMap<String, String>>>,
+    pub pages: Option<HashMap<String, String>>,
+}
+
+// Answer 41 for everything, or only paths starting with one of paths, while
+// enabled or while a .maintenance file is in dir. allow lists client cert
+// fingerprints and IP ranges that still get through.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Maintenance {
+    pub enabled: Option<bool>,
+    pub paths: Option<Vec<String>>,
+    pub message: Option<String>,
+    pub allow: Option<Vec<String>>,
+}
+
+// Timeouts in seconds. read is for the request line, handler for CGI, SCGI and
+// proxy upstreams, write for how long a client can go without reading any of
+// the response and lifetime caps the whole connection.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Timeouts {
+    pub read: Option<u64>,
+    pub handler: Option<u64>,
+    pub write: Option<u64>,
+    pub lifetime: Option<u64>,
+}
+
+impl Timeouts {
+    // Fields set in other take precedence over ours.
+    pub fn merge(&self, other: &Timeouts) -> Timeouts {
+        Timeouts {
+            read: other.read.or(self.read),
+            handler: other.handler.or(self.handler),
+            write: other.write.or(self.write),
+            lifetime: other.lifetime.or(self.lifetime),
+        }
+    }
+
+    pub fn read(&self) -> Duration {
+        Duration::from_secs(self.read.unwrap_or(5))
+    }
+
+    pub fn handler(&self) -> Duration {
+        Duration::from_secs(self.handler.unwrap_or(5))
+    }
+
+    pub fn write(&self) -> Option<Duration> {
+        self.write.map(Duration::from_secs)
+    }
+
+    pub fn lifetime(&self) -> Option<Duration> {
+        self.lifetime.map(Duration::from_secs)
+    }
+}
+
+// rate is requests per second and burst how many can be made at once. key is
+// "ip" or "cert", clients without a cert are limited by IP. IPv6 clients are
+// grouped by their prefix6 network.
+#[derive(Debug, Deserialize, Clone)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: Option<f64>,
+    pub key: Option<String>,
+    pub prefix6: Option<u32>,
+}
+
+// Addresses and CIDR ranges that may or may not connect. Denied clients get
+// status, 53 by default, with meta, or with close the connection is closed
+// without an answer.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Access {
+    pub allow: Option<Vec<String>>,
+    pub deny: Option<Vec<String>>,
+    pub status: Option<u8>,
+    pub meta: Option<String>,
+    pub close: Option<bool>,
+}
+
+// Clients with max bad outcomes within window seconds are banned for time
+// seconds. statuses are the response codes that count and tls is whether
+// failed handshakes do. Addresses in ignore are never banned.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Ban {
+    pub max: Option<usize>,
+    pub window: Option<u64>,
+    pub time: Option<u64>,
+    pub statuses: Option<Vec<u8>>,
+    pub tls: Option<bool>,
+    pub ignore: Option<Vec<String>>,
+    pub file: Option<String>,
+}
+
+// Limits on open connections in total and per client address. Clients over a
+// limit are disconnected, or if status is set get it with meta before their
+// request is read.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Connections {
+    pub max: Option<usize>,
+    pub per_ip: Option<usize>,
+    pub status: Option<u8>,
+    pub meta: Option<String>,
+}
+
+// Bytes per second. rate is shared by every connection and connection is the
+// most one connection gets. burst is how much can be sent at once and defaults
+// to a second's worth.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Bandwidth {
+    pub rate: Option<u64>,
+    pub connection: Option<u64>,
+    pub burst: Option<u64>,
+}
+
+impl Bandwidth {
+    fn shared(&self) -> Option<Arc<Throttle>> {
+        let rate = self.rate.filter(|r| *r > 0)?;
+        Some(Arc::new(Throttle::new(rate, self.burst)))
+    }
+}
+
+// Connections from the trusted ranges have to start with a PROXY header and
+// the client address in it is used instead of theirs.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ProxyProtocol {
+    pub trusted: Vec<String>,
+}
+
+// Where a log goes, stdout without file. rotate is "hourly" or "daily" and
+// max_size is in bytes. keep is how many rotated files are kept.
+#[derive(Debug, Deserialize, Clone)]
+pub struct LogOutput {
+    pub file: Option<String>,
+    pub rotate: Option<String>,
+    pub max_size: Option<u64>,
+    pub keep: Option<usize>,
+}
+
+// One record per request written when it's done. format is "logfmt", "json"
+// or "clf".
+#[derive(Debug, Deserialize, Clone)]
+pub struct AccessLog {
+    pub format: Option<String>,
+    #[serde(flatten)]
+    pub output: LogOutput,
+}
+
+// Another place to take connections. kind is "tls", "tcp" or "unix". tcp and
+// unix are cleartext for use behind a TLS terminator and the vhost comes from
+// the request. Only clients in trusted can use tcp ones. With proxy_protocol
+// connections start with a PROXY header, on tls listeners only those from
+// trusted, and cert_tlv is the version 2 TLV type holding the client
+// certificate hash.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Listen {
+    pub kind: Option<String>,
+    pub addr: Option<String>,
+    pub path: Option<String>,
+    pub mode: Option<u32>,
+    pub trusted: Option<Vec<String>>,
+    pub proxy_protocol: Option<bool>,
+    pub cert_tlv: Option<u8>,
+}
+
+impl Listen {
+    pub fn kind(&self) -> &str {
+        self.kind.as_deref().unwrap_or("tls")
+    }
+
+    pub fn trusts(&self, ip: std::net::IpAddr) -> bool {
+        self.trusted.iter().flatten().any(|r| crate::util::ip_in(ip, r))
+    }
+}
+
+// index can be a single file name or a list to try in order
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Index {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct DirList {
+    pub enabled: Option<bool>,
+    pub header: Option<String>,
+    pub footer: Option<String>,
+    pub size: Option<bool>,
+    pub mtime: Option<bool>,
+    pub sort: Option<String>,
+    pub hide: Option<Vec<String>>,
+    pub titles: Option<bool>,
+}
+
+impl DirList {
+    // Fields set in other take precedence over ours.
+    pub fn merge(&self, other: &DirList) -> DirList {
+        DirList {
+            enabled: other.enabled.or(self.enabled),
+            header: other.header.clone().or_else(|| self.header.clone()),
+            footer: other.footer.clone().or_else(|| self.footer.clone()),
+            size: other.size.or(self.size),
+            mtime: other.mtime.or(self.mtime),
+            sort: other.sort.clone().or_else(|| self.sort.clone()),
+            hide: other.hide.clone().or_else(|| self.hide.clone()),
+            titles: other.titles.or(self.titles),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ServerCfg {
     pub port: u16,
     pub server: Server,
+    pub cache: Option<Arc<Cache>>,
+    pub redirect_rules: Vec<redirect::Rule>,
+    pub rewrite: Vec<rewrite::Rule>,
+    pub timeout: Timeouts,
+    pub limiter: Arc<Limiter>,
+    pub access: Option<Access>,
+    pub bandwidth: Option<Bandwidth>,
+    pub throttles: Vec<Arc<Throttle>>,
+}
+
+impl ServerCfg {
+    // The shared throttles plus one of its own for a new connection. The
+    // vhost's per connection rate replaces the server wide one.
+    pub fn throttles(&self) -> Vec<Arc<Throttle>> {
+        let mut t = self.throttles.clone();
+        let own = |b: &Bandwidth| b.connection.filter(|c| *c > 0).map(|c| (c, b.burst));
+        let conn = self.server.bandwidth.as_ref().and_then(own)
+            .or_else(|| self.bandwidth.as_ref().and_then(own));
+        if let Some((rate, burst)) = conn {
+            t.push(Arc::new(Throttle::new(rate, burst)));
+        }
+        t
+    }
+
+    // The timeouts for url path p. Use "" for the vhost wide ones.
+    pub fn timeouts(&self, p: &str) -> Timeouts {
+        let mut t = self.timeout.clone();
+        if let Some(v) = &self.server.timeout {
+            t = t.merge(v);
+        }
+        match self.server.timeouts.as_ref().and_then(|r| crate::util::prefix_match(r, p)) {
+            Some(r) => t.merge(r),
+            None => t,
+        }
+    }
+
+    pub fn index(&self) -> Vec<String> {
+        match &self.server.index {
+            Some(Index::One(i)) => vec![i.clone()],
+            Some(Index::Many(i)) => i.clone(),
+            None => vec!["index.gemini".to_string()],
+        }
+    }
+
+    // The listing settings for the directory at url path p.
+    pub fn dirlist(&self, p: &str) -> DirList {
+        let base = self.server.dirlist.clone().unwrap_or_default();
+        match self.server.dirlists.as_ref().and_then(|d| crate::util::prefix_match(d, p)) {
+            Some(d) => base.merge(d),
+            None => base,
+        }
+    }
 }
 
 impl Config {
@@ -51,17 +407,129 @@ impl Config {
         };
         return Ok(config);
     }
-    pub fn to_map(&self) -> HashMap<String, ServerCfg> {
+
+    // The main listener followed by the extra ones
+    pub fn listeners(&self) -> io::Result<Vec<Listen>> {
+        let main = Listen {
+            kind: None,
+            addr: Some(format!("{}:{}", self.host, self.port)),
+            path: None,
+            mode: None,
+            trusted: self.proxy_protocol.as_ref().map(|p| p.trusted.clone()),
+            proxy_protocol: Some(self.proxy_protocol.is_some()),
+            cert_tlv: None,
+        };
+        let mut all = vec![main];
+        all.extend(self.listen.iter().flatten().cloned());
+        for l in &all {
+            let name = l.addr.as_ref().or(l.path.as_ref()).cloned().unwrap_or_default();
+            let err = |what: &str| Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("listen {} {}", name, what),
+            ));
+            match (l.kind(), &l.addr, &l.path) {
+                ("tls", Some(_), None) | ("tcp", Some(_), None) | ("unix", None, Some(_)) => {}
+                ("tls", _, _) | ("tcp", _, _) => return err("needs addr and no path"),
+                ("unix", _, _) => return err("needs path and no addr"),
+                (k, _, _) => return err(&format!("has unknown kind {}", k)),
+            }
+            let trusted = l.trusted.as_deref().unwrap_or_default();
+            if let Some(r) = trusted.iter().find(|r| !access::valid_cidr(r)) {
+                return err(&format!("has invalid address range {}", r));
+            }
+            if trusted.is_empty() && (l.kind() == "tcp" || (l.kind() == "tls" && l.proxy_protocol.unwrap_or(false))) {
+                return err("needs trusted");
+            }
+        }
+        Ok(all)
+    }
+
+    pub fn to_map(&self) -> io::Result<HashMap<String, ServerCfg>> {
         let mut map = HashMap::new();
+        let cache = self.cache.as_ref().map(|c| Arc::new(Cache::new(c)));
+        let limiter = Arc::new(Limiter::new(self.ratelimit_entries));
+        let throttle = self.bandwidth.as_ref().and_then(|b| b.shared());
+        if let Some(a) = &self.access {
+            access::validate(a)?;
+        }
+        for r in self.proxy_protocol.iter().flat_map(|p| p.trusted.iter()) {
+            if !access::valid_cidr(r) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("proxy_protocol has invalid address range {}", r),
+                ));
+            }
+        }
+        for r in self.ban.iter().flat_map(|b| b.ignore.iter().flatten()) {
+            if !access::valid_cidr(r) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("ban ignore has invalid address range {}", r),
+                ));
+            }
+        }
+        if let Some(s) = self.connections.as_ref().and_then(|c| c.status) {
+            if Status::from_u8(s).is_none() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("connections has unknown status {}", s),
+                ));
+            }
+        }
         for srv in &self.server {
-            map.insert(
-                srv.hostname.clone(),
-                ServerCfg {
-                    port: self.port.clone(),
-                    server: srv.clone(),
-                },
-            );
+            let cfg = ServerCfg {
+                port: self.port,
+                server: srv.clone(),
+                cache: cache.clone(),
+                redirect_rules: redirect::compile(
+                    srv.redirect_rules.as_deref().unwrap_or_default(),
+                )?,
+                rewrite: rewrite::compile(srv.rewrite.as_deref().unwrap_or_default())?,
+                timeout: self.timeout.clone().unwrap_or_default(),
+                limiter: limiter.clone(),
+                access: self.access.clone(),
+                bandwidth: self.bandwidth.clone(),
+                throttles: throttle.iter().cloned()
+                    .chain(srv.bandwidth.as_ref().and_then(|b| b.shared()))
+                    .collect(),
+            };
+            let routes = srv.accesses.iter().flat_map(|a| a.values());
+            for a in srv.access.iter().chain(routes) {
+                access::validate(a)?;
+            }
+            let routes = srv.ratelimits.iter().flat_map(|r| r.values());
+            for l in srv.ratelimit.iter().chain(routes) {
+                ratelimit::validate(l)?;
+            }
+            // Anything that isn't a certificate fingerprint is an address range
+            let allow = srv.maintenance.iter().flat_map(|m| m.allow.iter().flatten());
+            for r in allow.filter(|r| !r.starts_with("SHA256:")) {
+                if !access::valid_cidr(r) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("maintenance for {} has invalid allow entry {}", srv.hostname, r),
+                    ));
+                }
+            }
+            for r in srv.response.iter().flatten() {
+                if Status::from_u8(r.status).is_none() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("response for {} has unknown status {}", r.path, r.status),
+                    ));
+                }
+            }
+            if let Some(v) = srv.proxy_header.filter(|v| *v != 1 && *v != 2) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("proxy_header for {} has unknown version {}", srv.hostname, v),
+                ));
+            }
+            for alias in srv.alias.iter().flatten() {
+                map.insert(alias.clone(), cfg.clone());
+            }
+            map.insert(srv.hostname.clone(), cfg);
         }
-        map
+        Ok(map)
     }
 }
@@ -1,19 +1,159 @@
+use std::fmt;
 use std::io;
 use std::marker::Unpin;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
 
-use tokio::net::TcpStream;
+use openssl::ssl::{NameType, SslRef};
 use tokio::prelude::*;
-use tokio_openssl::SslStream;
 
+use crate::config;
+use crate::logfile;
+use crate::logger;
+use crate::ratelimit::Throttle;
 use crate::status::Status;
+use crate::util;
+
+// Anything a connection can run over: TLS, plain TCP, a Unix socket or an
+// in memory pipe.
+pub trait Io: AsyncRead + AsyncWrite + Unpin + Send + Sync + fmt::Debug {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync + fmt::Debug> Io for T {}
+
+pub type Stream = Box<dyn Io>;
+
+// The client certificate, from the TLS session or from the front end that
+// terminated TLS
+#[derive(Debug, Clone)]
+pub struct ClientCert {
+    pub hash: String,
+    pub name: Option<String>,
+}
+
+impl ClientCert {
+    pub fn from_x509(x: &openssl::x509::X509) -> ClientCert {
+        let name = x
+            .subject_name()
+            .entries_by_nid(openssl::nid::Nid::COMMONNAME)
+            .last()
+            .map(|c| c.data().as_utf8().map(|n| n.to_string()).unwrap_or_default());
+        ClientCert {
+            hash: util::fingerhex(x),
+            name,
+        }
+    }
+}
+
+// Who is on the other end and how they got here. local_addr is where they
+// connected to. protocol and cipher are only set when TLS was done here, cert
+// can also come from a TLS terminator in front.
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub addr: SocketAddr,
+    pub local_addr: SocketAddr,
+    pub sni: Option<String>,
+    pub cert: Option<ClientCert>,
+    pub protocol: Option<String>,
+    pub cipher: Option<String>,
+}
+
+impl PeerInfo {
+    pub fn new(addr: SocketAddr, local_addr: SocketAddr) -> PeerInfo {
+        PeerInfo {
+            addr,
+            local_addr,
+            sni: None,
+            cert: None,
+            protocol: None,
+            cipher: None,
+        }
+    }
+
+    // Fill in what the TLS session knows about the client
+    pub fn set_tls(&mut self, ssl: &SslRef) {
+        self.sni = ssl.servername(NameType::HOST_NAME).map(|s| s.to_string());
+        self.cert = ssl.peer_certificate().map(|x| ClientCert::from_x509(&x));
+        self.protocol = Some(ssl.version_str().to_string());
+        self.cipher = ssl.current_cipher().map(|c| c.name().to_string());
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.protocol.is_some()
+    }
+
+    // The client's address, or None for Unix socket clients that didn't come
+    // with a PROXY header. Those all share a placeholder address so they're
+    // left out of anything done per address.
+    pub fn ip(&self) -> Option<IpAddr> {
+        Some(self.addr.ip()).filter(|ip| !ip.is_unspecified())
+    }
+}
+
+// What the access log needs to know about a request. status and meta are
+// taken from the response header as it's sent.
+#[derive(Debug, Default)]
+pub struct Record {
+    pub id: String,
+    pub listener: String,
+    pub vhost: String,
+    pub request: String,
+    pub handler: Option<&'static str>,
+    pub status: Option<u8>,
+    pub meta: String,
+    pub bytes: u64,
+}
+
+impl Record {
+    fn header(&mut self, body: &[u8]) {
+        let end = body.windows(2).position(|w| w == b"\r\n").unwrap_or(body.len());
+        let line = String::from_utf8_lossy(&body[..end]);
+        let (code, meta) = line.split_once(' ').unwrap_or((&line, ""));
+        self.status = code.parse().ok();
+        self.meta = meta.to_string();
+    }
+}
 
 pub struct Connection {
-    pub stream: SslStream<TcpStream>,
-    pub peer_addr: SocketAddr,
+    pub stream: Stream,
+    pub peer: PeerInfo,
+    pub record: Record,
+    pub errors: Option<config::Errors>,
+    pub lang: Option<String>,
+    pub write_timeout: Option<Duration>,
+    // The last status sent by the server itself
+    pub status: Option<Status>,
+    pub throttles: Vec<Arc<Throttle>>,
 }
 
+// Throttled responses are sent in pieces this big
+const THROTTLE_CHUNK: usize = 16 * 1024;
+
 impl Connection {
+    // A connection without any vhost settings until set_vhost is called
+    pub fn new<S: Io + 'static>(stream: S, peer: PeerInfo) -> Connection {
+        Connection {
+            stream: Box::new(stream),
+            peer,
+            record: Record::default(),
+            errors: None,
+            lang: None,
+            write_timeout: None,
+            status: None,
+            throttles: Vec::new(),
+        }
+    }
+
+    // Take the per vhost settings from srv
+    pub fn set_vhost(&mut self, srv: &config::ServerCfg) {
+        self.record.vhost = srv.server.hostname.clone();
+        logfile::set_vhost(&srv.server.hostname);
+        self.errors = srv.server.errors.clone();
+        self.lang = srv.server.lang.clone();
+        self.write_timeout = srv.timeouts("").write();
+        self.throttles = srv.throttles();
+    }
+
     pub async fn send_status(&mut self, stat: Status, meta: Option<&str>) -> Result<(), io::Error> {
         self.send_body(stat, meta, None).await?;
         Ok(())
@@ -25,9 +165,22 @@ impl Connection {
         meta: Option<&str>,
         body: Option<String>,
     ) -> Result<(), io::Error> {
+        self.status = Some(stat);
+        if meta.is_none() && body.is_none() {
+            if let Some((lang, page)) = self.error_page(stat).await {
+                let mime = match lang {
+                    Some(l) => format!("text/gemini; lang={}", l),
+                    None => "text/gemini".to_string(),
+                };
+                self.send_raw(format!("{} {}\r\n", Status::Success as u8, mime).as_bytes())
+                    .await?;
+                self.send_raw(&page).await?;
+                return Ok(());
+            }
+        }
         let meta = match meta {
-            Some(m) => m,
-            None => &stat.to_str(),
+            Some(m) => m.to_string(),
+            None => self.error_meta(stat),
         };
         self.send_raw(format!("{} {}\r\n", stat as u8, meta).as_bytes())
             .await?;
@@ -37,14 +190,203 @@ impl Connection {
         Ok(())
     }
 
+    // The configured meta for stat in the connection's language, or the
+    // built in one.
+    fn error_meta(&self, stat: Status) -> String {
+        let code = (stat as u8).to_string();
+        let errors = match &self.errors {
+            Some(e) => e,
+            None => return stat.to_str().to_string(),
+        };
+        let by_lang = match (&errors.lang, &self.lang) {
+            (Some(l), Some(lang)) => l.get(lang).and_then(|m| m.get(&code)),
+            _ => None,
+        };
+        by_lang
+            .or_else(|| errors.meta.as_ref().and_then(|m| m.get(&code)))
+            .cloned()
+            .unwrap_or_else(|| stat.to_str().to_string())
+    }
+
+    // A replacement page for an error status, preferring a version in the
+    // connection's language e.g. 51.fr.gmi over 51.gmi.
+    async fn error_page(&self, stat: Status) -> Option<(Option<String>, Vec<u8>)> {
+        let code = stat as u8;
+        if !(40..60).contains(&code) {
+            return None;
+        }
+        let path = self.errors.as_ref()?.pages.as_ref()?.get(&code.to_string())?;
+        if let Some(lang) = &self.lang {
+            let (stem, _, ext) = util::split_lang(path);
+            if let Ok(p) = tokio::fs::read(format!("{}.{}.{}", stem, lang, ext)).await {
+                return Some((Some(lang.clone()), p));
+            }
+        }
+        match tokio::fs::read(path).await {
+            Ok(p) => Some((self.lang.clone(), p)),
+            Err(e) => {
+                log::error!("Can't read error page {}: {}", path, e);
+                None
+            }
+        }
+    }
+
     pub async fn send_raw(&mut self, body: &[u8]) -> Result<(), io::Error> {
-        self.stream.write_all(body).await?;
+        if self.record.bytes == 0 && !body.is_empty() {
+            self.record.header(body);
+        }
+        let mut sent = 0;
+        // Everything before allowed has been accounted for by the throttles
+        let mut allowed = if self.throttles.is_empty() { body.len() } else { 0 };
+        while sent < body.len() {
+            if sent == allowed {
+                allowed = (sent + THROTTLE_CHUNK).min(body.len());
+                let wait = self.throttles.iter().map(|t| t.reserve(allowed - sent)).max();
+                if let Some(w) = wait.filter(|w| *w > Duration::from_secs(0)) {
+                    tokio::time::delay_for(w).await;
+                }
+            }
+            let write = self.stream.write(&body[sent..allowed]);
+            let len = match self.write_timeout {
+                Some(t) => match tokio::time::timeout(t, write).await {
+                    Ok(r) => r?,
+                    Err(_) => {
+                        logger::timeout(self.peer.addr, "write", "");
+                        return Err(io::Error::from(io::ErrorKind::TimedOut));
+                    }
+                },
+                None => write.await?,
+            };
+            if len == 0 {
+                return Err(io::Error::from(io::ErrorKind::WriteZero));
+            }
+            sent += len;
+            self.record.bytes += len as u64;
+        }
         self.stream.flush().await?;
         Ok(())
     }
 
-    pub async fn send_stream<S: AsyncRead + Unpin>(&mut self, reader: &mut S) -> Result<(), io::Error> {
-        tokio::io::copy(reader, &mut self.stream).await?;
-        Ok(())
+    // Copy reader to the client until it ends, or fail with TimedOut if it
+    // goes idle for longer than idle.
+    pub async fn send_stream<S: AsyncRead + Unpin>(
+        &mut self,
+        reader: &mut S,
+        idle: Duration,
+    ) -> Result<(), io::Error> {
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let len = tokio::time::timeout(idle, reader.read(&mut buf))
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+            if len == 0 {
+                return Ok(());
+            }
+            self.send_raw(&buf[..len]).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::pin::Pin;
+    use std::sync::Mutex;
+    use std::task::{Context, Poll};
+
+    // A stream that reads from input and keeps what's written for checking
+    #[derive(Debug, Default)]
+    struct Mem {
+        input: io::Cursor<Vec<u8>>,
+        output: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl AsyncRead for Mem {
+        fn poll_read(mut self: Pin<&mut Self>, _: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            Poll::Ready(io::Read::read(&mut self.input, buf))
+        }
+    }
+
+    impl AsyncWrite for Mem {
+        fn poll_write(self: Pin<&mut Self>, _: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.output.lock().unwrap().extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    pub(crate) fn connection(input: &[u8]) -> (Connection, Arc<Mutex<Vec<u8>>>) {
+        let mem = Mem {
+            input: io::Cursor::new(input.to_vec()),
+            ..Mem::default()
+        };
+        let output = mem.output.clone();
+        let peer = PeerInfo::new("192.0.2.1:5000".parse().unwrap(), "192.0.2.2:1965".parse().unwrap());
+        (Connection::new(mem, peer), output)
+    }
+
+    pub(crate) fn run<F: std::future::Future>(f: F) -> F::Output {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_time()
+            .build()
+            .unwrap();
+        rt.block_on(f)
+    }
+
+    #[test]
+    fn send_status() {
+        let (mut con, out) = connection(b"");
+        run(con.send_status(Status::NotFound, None)).unwrap();
+        assert_eq!(&out.lock().unwrap()[..], b"51 Not Found!\r\n");
+        assert_eq!(con.status.map(|s| s as u8), Some(51));
+        assert_eq!(con.record.status, Some(51));
+        assert_eq!(con.record.meta, "Not Found!");
+        assert_eq!(con.record.bytes, 15);
+    }
+
+    #[test]
+    fn send_body() {
+        let (mut con, out) = connection(b"");
+        run(con.send_body(Status::Success, Some("text/gemini"), Some("# Hi\n".to_string()))).unwrap();
+        assert_eq!(&out.lock().unwrap()[..], b"20 text/gemini\r\n# Hi\n");
+        assert_eq!(con.record.status, Some(20));
+        assert_eq!(con.record.meta, "text/gemini");
+        assert_eq!(con.record.bytes, 21);
+    }
+
+    #[test]
+    fn error_meta_in_language() {
+        let (mut con, out) = connection(b"");
+        let mut fr = HashMap::new();
+        fr.insert("51".to_string(), "Introuvable".to_string());
+        let mut lang = HashMap::new();
+        lang.insert("fr".to_string(), fr);
+        con.errors = Some(config::Errors {
+            meta: None,
+            lang: Some(lang),
+            pages: None,
+        });
+        con.lang = Some("fr".to_string());
+        run(con.send_status(Status::NotFound, None)).unwrap();
+        assert_eq!(&out.lock().unwrap()[..], b"51 Introuvable\r\n");
+    }
+
+    #[test]
+    fn throttled_send_raw() {
+        let (mut con, out) = connection(b"");
+        con.throttles = vec![Arc::new(Throttle::new(1 << 30, None))];
+        let body: Vec<u8> = (0..THROTTLE_CHUNK * 3 + 7).map(|i| i as u8).collect();
+        run(con.send_raw(&body)).unwrap();
+        assert_eq!(*out.lock().unwrap(), body);
+        assert_eq!(con.record.bytes, body.len() as u64);
     }
 }
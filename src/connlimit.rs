@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::config;
+use crate::util;
+
+#[derive(Default)]
+struct Inner {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+// Counts open connections, in total and per client address
+pub struct Counter {
+    max: Option<usize>,
+    max_per_ip: Option<usize>,
+    inner: Mutex<Inner>,
+}
+
+// An open connection. It stops being counted when this is dropped.
+pub struct Slot {
+    counter: Arc<Counter>,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut inner = self.counter.inner.lock().unwrap();
+        inner.total -= 1;
+        if let Some(ip) = self.ip {
+            if let Some(n) = inner.per_ip.get_mut(&ip) {
+                *n -= 1;
+                if *n == 0 {
+                    inner.per_ip.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+impl Counter {
+    pub fn new(cfg: Option<&config::Connections>) -> Counter {
+        Counter {
+            max: cfg.and_then(|c| c.max),
+            max_per_ip: cfg.and_then(|c| c.per_ip),
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    // Count a new connection from ip, or return None if that would go over
+    // one of the limits. Clients without an address only count to the total.
+    pub fn open(self: &Arc<Self>, ip: Option<IpAddr>) -> Option<Slot> {
+        let ip = ip.map(util::canonical_ip);
+        let mut inner = self.inner.lock().unwrap();
+        let from_ip = ip.and_then(|ip| inner.per_ip.get(&ip).copied()).unwrap_or(0);
+        if self.max.is_some_and(|m| inner.total >= m) || self.max_per_ip.is_some_and(|m| from_ip >= m) {
+            return None;
+        }
+        inner.total += 1;
+        if let Some(ip) = ip {
+            inner.per_ip.insert(ip, from_ip + 1);
+        }
+        Some(Slot {
+            counter: self.clone(),
+            ip,
+        })
+    }
+
+    pub fn total(&self) -> usize {
+        self.inner.lock().unwrap().total
+    }
+
+    // The total and the addresses with the most open connections
+    pub fn stats(&self, top: usize) -> String {
+        let inner = self.inner.lock().unwrap();
+        let mut ips: Vec<_> = inner.per_ip.iter().collect();
+        ips.sort_by(|a, b| b.1.cmp(a.1));
+        let mut s = format!("connections={} clients={}\n", inner.total, inner.per_ip.len());
+        for (ip, n) in ips.into_iter().take(top) {
+            s.push_str(&format!("{} {}\n", ip, n));
+        }
+        s
+    }
+}
@@ -0,0 +1,151 @@
+use std::cmp::Reverse;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::config::DirList;
+use crate::dirmeta;
+use crate::util;
+
+struct Entry {
+    name: String,
+    link: String,
+    label: String,
+    is_dir: bool,
+    len: u64,
+    mtime: SystemTime,
+}
+
+// The first level one heading of a gemtext file
+fn title(path: &Path) -> Option<String> {
+    match path.extension()?.to_str()? {
+        "gmi" | "gemini" => {}
+        _ => return None,
+    }
+    let reader = BufReader::new(File::open(path).ok()?);
+    for line in reader.lines().take(64) {
+        let line = line.ok()?;
+        if line.starts_with('#') && !line.starts_with("##") {
+            let t = line.trim_start_matches('#').trim();
+            if !t.is_empty() {
+                return Some(t.to_string());
+            }
+        }
+    }
+    None
+}
+
+// A listing is static if it only changes when the directory itself does, so
+// it can be cached against the directory's modification time. Editing a file
+// doesn't touch the directory so only name order is.
+pub fn is_static(cfg: &DirList) -> bool {
+    matches!(cfg.sort.as_deref(), None | Some("name"))
+        && cfg.header.is_none()
+        && cfg.footer.is_none()
+        && !cfg.size.unwrap_or(false)
+        && !cfg.mtime.unwrap_or(false)
+        && !cfg.titles.unwrap_or(false)
+}
+
+pub fn hidden(cfg: &DirList, name: &str) -> bool {
+    if name == dirmeta::FILE || name == ".maintenance" || name.ends_with(".gone") {
+        return true;
+    }
+    if Some(name) == cfg.header.as_deref() || Some(name) == cfg.footer.as_deref() {
+        return true;
+    }
+    match &cfg.hide {
+        Some(h) => h.iter().any(|p| util::glob_match(p, name)),
+        None => false,
+    }
+}
+
+// With clean set gemtext files are linked without their extension.
+pub async fn listing(path: &Path, u: &url::Url, cfg: &DirList, clean: bool) -> Result<String, io::Error> {
+    let mut entries: Vec<Entry> = Vec::new();
+
+    for file in fs::read_dir(path)?.flatten() {
+        let m = file.metadata()?;
+        let perm = m.permissions();
+        if perm.mode() & 0o0444 != 0o0444 {
+            continue;
+        }
+        let name = match file.file_name().into_string() {
+            Ok(n) => n,
+            Err(_) => continue,
+        };
+        if hidden(cfg, &name) {
+            continue;
+        }
+        let target = match util::clean_name(&name) {
+            Some(n) if clean && m.is_file() => n,
+            _ => name.clone(),
+        };
+        // Anchor the join so names like "a:b" aren't taken as a scheme
+        let link = match u.join(&format!("./{}", target)) {
+            Ok(l) => l,
+            Err(_) => continue,
+        };
+        let (link, mut label) = if m.is_dir() {
+            (format!("{}/", link), format!("{}/", name))
+        } else {
+            (link.to_string(), name.clone())
+        };
+        if cfg.titles.unwrap_or(false) && m.is_file() {
+            if let Some(t) = title(&file.path()) {
+                label = t;
+            }
+        }
+        entries.push(Entry {
+            name,
+            link,
+            label,
+            is_dir: m.is_dir(),
+            len: m.len(),
+            mtime: m.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+        });
+    }
+
+    match cfg.sort.as_deref() {
+        Some("date") => entries.sort_by_key(|e| (!e.is_dir, Reverse(e.mtime))),
+        Some("size") => entries.sort_by_key(|e| (!e.is_dir, Reverse(e.len))),
+        _ => entries.sort_by(|a, b| (!a.is_dir, &a.name).cmp(&(!b.is_dir, &b.name))),
+    }
+
+    let header = cfg
+        .header
+        .as_ref()
+        .and_then(|h| fs::read_to_string(path.join(h)).ok());
+    let mut list = match header {
+        Some(h) => h,
+        None => format!("# Directory Listing\r\n\r\nPath: {}\r\n", u.path()),
+    };
+    if !list.ends_with('\n') {
+        list.push_str("\r\n");
+    }
+    list.push_str("\r\n");
+
+    for e in entries {
+        let mut info = Vec::new();
+        if cfg.size.unwrap_or(false) && !e.is_dir {
+            info.push(util::fmt_size(e.len));
+        }
+        if cfg.mtime.unwrap_or(false) {
+            info.push(util::fmt_time(e.mtime)[..16].to_string());
+        }
+        if info.is_empty() {
+            list.push_str(&format!("=> {} {}\r\n", e.link, e.label));
+        } else {
+            list.push_str(&format!("=> {} {} ({})\r\n", e.link, e.label, info.join(", ")));
+        }
+    }
+
+    if let Some(f) = cfg.footer.as_ref().and_then(|f| fs::read_to_string(path.join(f)).ok()) {
+        list.push_str("\r\n");
+        list.push_str(&f);
+    }
+
+    Ok(list)
+}
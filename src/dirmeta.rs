@@ -0,0 +1,106 @@
+use std::path::Path;
+
+use crate::util;
+
+// Name of the per-directory metadata file
+pub const FILE: &str = ".gemserv";
+
+#[derive(Debug, Deserialize, Default)]
+struct MetaFile {
+    lang: Option<String>,
+    charset: Option<String>,
+    rule: Option<Vec<Rule>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Rule {
+    pattern: String,
+    mime: Option<String>,
+    lang: Option<String>,
+    charset: Option<String>,
+    gone: Option<bool>,
+    redirect: Option<String>,
+    permanent: Option<bool>,
+    meta: Option<String>,
+}
+
+// Settings for one path gathered from every metadata file between the vhost
+// root and the path. Files deeper in the tree take precedence.
+#[derive(Debug, Default, Clone)]
+pub struct DirMeta {
+    pub mime: Option<String>,
+    pub lang: Option<String>,
+    pub charset: Option<String>,
+    pub gone: bool,
+    pub redirect: Option<(String, bool)>,
+    pub meta: Option<String>,
+}
+
+impl DirMeta {
+    fn apply(&mut self, file: MetaFile, rel: &str) {
+        if file.lang.is_some() {
+            self.lang = file.lang;
+        }
+        if file.charset.is_some() {
+            self.charset = file.charset;
+        }
+        for r in file.rule.unwrap_or_default() {
+            if !util::glob_match(&r.pattern, rel) {
+                continue;
+            }
+            if r.mime.is_some() {
+                self.mime = r.mime;
+            }
+            if r.lang.is_some() {
+                self.lang = r.lang;
+            }
+            if r.charset.is_some() {
+                self.charset = r.charset;
+            }
+            if let Some(g) = r.gone {
+                self.gone = g;
+            }
+            if let Some(re) = r.redirect {
+                self.redirect = Some((re, r.permanent.unwrap_or(false)));
+            }
+            if r.meta.is_some() {
+                self.meta = r.meta;
+            }
+        }
+    }
+}
+
+// Collect the metadata for path, which doesn't have to exist. Rule patterns
+// are matched against the path relative to the directory of the metadata file.
+pub fn lookup(root: &Path, path: &Path) -> DirMeta {
+    let mut dm = DirMeta::default();
+    let rel = match path.strip_prefix(root) {
+        Ok(r) => r,
+        Err(_) => return dm,
+    };
+    let mut dir = root.to_path_buf();
+    let mut parts: Vec<_> = rel.components().collect();
+    // A directory's own metadata file applies to its listing
+    if !path.is_dir() {
+        parts.pop();
+    }
+    for i in 0..=parts.len() {
+        if i > 0 {
+            dir.push(parts[i - 1]);
+        }
+        let s = match std::fs::read_to_string(dir.join(FILE)) {
+            Ok(s) => s,
+            Err(_) => continue,
+        };
+        let file: MetaFile = match toml::from_str(&s) {
+            Ok(f) => f,
+            Err(e) => {
+                log::warn!("Error in {}: {}", dir.join(FILE).display(), e);
+                continue;
+            }
+        };
+        let r = path.strip_prefix(&dir).unwrap_or(path);
+        dm.apply(file, &r.to_string_lossy());
+    }
+    dm
+}
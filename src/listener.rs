@@ -0,0 +1,48 @@
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::os::unix::fs::PermissionsExt;
+
+use tokio::net::{TcpListener, UnixListener};
+
+use crate::config;
+use crate::conn::Stream;
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub async fn bind(l: &config::Listen) -> io::Result<Listener> {
+        match (&l.addr, &l.path) {
+            (_, Some(path)) => {
+                let _ = fs::remove_file(path);
+                let listener = UnixListener::bind(path)?;
+                if let Some(mode) = l.mode {
+                    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+                }
+                Ok(Listener::Unix(listener))
+            }
+            (Some(addr), None) => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+            (None, None) => Err(io::Error::from(io::ErrorKind::AddrNotAvailable)),
+        }
+    }
+
+    // Accept a connection along with the client's address and the one it
+    // connected to. Unix sockets have neither so unspecified ones stand in.
+    pub async fn accept(&mut self) -> io::Result<(Stream, SocketAddr, SocketAddr)> {
+        match self {
+            Listener::Tcp(l) => {
+                let (s, peer) = l.accept().await?;
+                let local = s.local_addr()?;
+                Ok((Box::new(s), peer, local))
+            }
+            Listener::Unix(l) => {
+                let (s, _) = l.accept().await?;
+                let none = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+                Ok((Box::new(s), none, none))
+            }
+        }
+    }
+}
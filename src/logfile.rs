@@ -0,0 +1,255 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::config;
+use crate::util;
+
+const KEEP: usize = 7;
+
+tokio::task_local! {
+    // The vhost the current connection is for, so its log messages can go to
+    // that vhost's error log
+    static VHOST: RefCell<Option<String>>;
+}
+
+// Run a connection's future with somewhere to note its vhost
+pub async fn scope<F: Future>(f: F) -> F::Output {
+    VHOST.scope(RefCell::new(None), f).await
+}
+
+pub fn set_vhost(name: &str) {
+    let _ = VHOST.try_with(|v| *v.borrow_mut() = Some(name.to_string()));
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+struct Inner {
+    file: File,
+    size: u64,
+    // When the current file was started
+    since: u64,
+}
+
+// A log file that's appended to. It's rotated when it gets over max_size or
+// when a new period starts, moving it to path.1, the older ones up by one and
+// dropping any past keep.
+pub struct LogFile {
+    path: PathBuf,
+    max_size: Option<u64>,
+    period: Option<u64>,
+    keep: usize,
+    inner: Mutex<Inner>,
+}
+
+fn open_append(path: &PathBuf) -> io::Result<Inner> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let meta = file.metadata()?;
+    let since = match meta.len() {
+        0 => now(),
+        _ => meta
+            .modified()
+            .ok()
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or_else(now),
+    };
+    Ok(Inner {
+        file,
+        size: meta.len(),
+        since,
+    })
+}
+
+impl LogFile {
+    fn open(path: &str, cfg: &config::LogOutput) -> io::Result<LogFile> {
+        let period = match cfg.rotate.as_deref() {
+            None => None,
+            Some("hourly") => Some(3600),
+            Some("daily") => Some(86400),
+            Some(r) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("log rotate must be hourly or daily, not {}", r),
+                ))
+            }
+        };
+        let path = PathBuf::from(path);
+        let inner = open_append(&path)?;
+        Ok(LogFile {
+            path,
+            max_size: cfg.max_size,
+            period,
+            keep: cfg.keep.unwrap_or(KEEP),
+            inner: Mutex::new(inner),
+        })
+    }
+
+    fn numbered(&self, n: usize) -> PathBuf {
+        let mut p = self.path.clone().into_os_string();
+        p.push(format!(".{}", n));
+        PathBuf::from(p)
+    }
+
+    fn rotate(&self, inner: &mut Inner) -> io::Result<()> {
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(self.numbered(self.keep));
+            for n in (1..self.keep).rev() {
+                let _ = fs::rename(self.numbered(n), self.numbered(n + 1));
+            }
+            fs::rename(&self.path, self.numbered(1))?;
+        }
+        *inner = open_append(&self.path)?;
+        Ok(())
+    }
+
+    // Append a line, rotating first if it's due. Errors can't go to the log
+    // so they go to stderr.
+    pub fn write(&self, line: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        let len = line.len() as u64 + 1;
+        let full = self.max_size.is_some_and(|m| inner.size > 0 && inner.size + len > m);
+        let old = self.period.is_some_and(|p| now() / p != inner.since / p);
+        if full || old {
+            if let Err(e) = self.rotate(&mut inner) {
+                eprintln!("Can't rotate {}: {}", self.path.display(), e);
+            }
+        }
+        let mut buf = String::with_capacity(line.len() + 1);
+        buf.push_str(line);
+        buf.push('\n');
+        match inner.file.write_all(buf.as_bytes()) {
+            Ok(()) => inner.size += len,
+            Err(e) => eprintln!("Can't write {}: {}", self.path.display(), e),
+        }
+    }
+
+    // Start writing to a new file at path, for after it's been moved away
+    pub fn reopen(&self) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        *inner = open_append(&self.path)?;
+        Ok(())
+    }
+}
+
+// The open log files by path, so logs pointed at the same file share it
+#[derive(Default)]
+pub struct Files {
+    open: HashMap<String, Arc<LogFile>>,
+}
+
+impl Files {
+    // The file cfg points to, or None for stdout
+    pub fn open(&mut self, cfg: &config::LogOutput) -> io::Result<Option<Arc<LogFile>>> {
+        let path = match &cfg.file {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+        if let Some(f) = self.open.get(path) {
+            return Ok(Some(f.clone()));
+        }
+        let f = Arc::new(LogFile::open(path, cfg).map_err(|e| {
+            io::Error::new(e.kind(), format!("Can't open log {}: {}", path, e))
+        })?);
+        self.open.insert(path.clone(), f.clone());
+        Ok(Some(f))
+    }
+
+    // Reopen every file on SIGUSR1, for logrotate and the like. The handler is
+    // installed even without files so the signal doesn't end the server.
+    // It has to be called from inside the runtime.
+    pub fn reopen_on_signal(self) -> io::Result<impl Future<Output = ()>> {
+        let mut usr1 = signal(SignalKind::user_defined1())?;
+        Ok(async move {
+            while usr1.recv().await.is_some() {
+                self.reopen_all();
+            }
+        })
+    }
+
+    fn reopen_all(&self) {
+        if self.open.is_empty() {
+            return;
+        }
+        for (path, f) in self.open.iter() {
+            if let Err(e) = f.reopen() {
+                log::error!("Can't reopen log {}: {}", path, e);
+            }
+        }
+        log::info!("Reopened log files");
+    }
+}
+
+// Sends log messages to the error log of the vhost they're about, or the
+// global one, or stdout like before if there's neither.
+pub struct Logger {
+    level: log::Level,
+    global: Option<Arc<LogFile>>,
+    vhosts: HashMap<String, Arc<LogFile>>,
+}
+
+impl Logger {
+    // None if no error_log is set anywhere
+    pub fn new(cfg: &config::Config, level: log::Level, files: &mut Files) -> io::Result<Option<Logger>> {
+        let global = match &cfg.error_log {
+            Some(e) => files.open(e)?,
+            None => None,
+        };
+        let mut vhosts = HashMap::new();
+        for srv in cfg.server.iter() {
+            if let Some(f) = srv.error_log.as_ref().map(|e| files.open(e)).transpose()?.flatten() {
+                vhosts.insert(srv.hostname.clone(), f);
+            }
+        }
+        if global.is_none() && vhosts.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Logger { level, global, vhosts }))
+    }
+
+    pub fn init(self) -> Result<(), log::SetLoggerError> {
+        log::set_max_level(self.level.to_level_filter());
+        log::set_logger(Box::leak(Box::new(self)))
+    }
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let (y, m, d, hh, mm, ss) = util::civil(SystemTime::now());
+        let line = format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} {:<5} [{}] {}",
+            y, m, d, hh, mm, ss, record.level(), record.target(), record.args()
+        );
+        let vhost = VHOST
+            .try_with(|v| v.borrow().as_ref().and_then(|h| self.vhosts.get(h)).cloned())
+            .ok()
+            .flatten();
+        match vhost.as_ref().or(self.global.as_ref()) {
+            Some(f) => f.write(&line),
+            None => println!("{}", line),
+        }
+    }
+
+    fn flush(&self) {}
+}
@@ -8,3 +8,22 @@ pub fn logger(addr: SocketAddr, stat: status::Status, req: &str) {
         _ => warn!("remote={} status={} request={}", addr, stat as u8, req),
     }
 }
+
+pub fn rewrite(addr: SocketAddr, req: &str, to: &url::Url) {
+    info!("remote={} rewrite={} request={}", addr, to, req)
+}
+
+// kind is which timeout fired: read, handler, write or lifetime
+pub fn timeout(addr: SocketAddr, kind: &str, req: &str) {
+    warn!("remote={} timeout={} request={}", addr, kind, req)
+}
+
+// The client was refused by an access rule and the connection closed
+pub fn denied(addr: SocketAddr, req: &str) {
+    warn!("remote={} denied request={}", addr, req)
+}
+
+// The client went over a connection limit. open is how many are open in total.
+pub fn limit(addr: SocketAddr, open: usize) {
+    warn!("remote={} limit=connections open={}", addr, open)
+}
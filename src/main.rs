@@ -1,40 +1,56 @@
 #[macro_use]
 extern crate serde_derive;
 
-use futures_util::future::TryFutureExt;
+use futures_util::future::{self, TryFutureExt};
 use mime_guess;
-use openssl::ssl::NameType;
+use std::collections::HashMap;
 use std::env;
 use std::fs;
 use std::fs::File;
 use std::io::{self, BufRead, BufReader};
-use std::net::ToSocketAddrs;
+use std::net::SocketAddr;
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
-use tokio::net::TcpListener;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use openssl::ssl::SslAcceptor;
 use tokio::prelude::*;
 use tokio::runtime;
 use url::Url;
 
+mod access;
+mod accesslog;
+mod admin;
+mod ban;
+mod cache;
 mod cgi;
 mod config;
+mod connlimit;
+mod dirlist;
+mod dirmeta;
 mod status;
 use status::Status;
 mod conn;
+mod listener;
+mod logfile;
 mod logger;
+mod proxyproto;
+mod ratelimit;
+mod redirect;
 mod revproxy;
+mod rewrite;
 mod tls;
 mod util;
 
-fn get_mime(path: &PathBuf) -> String {
+fn get_mime(srv: &config::ServerCfg, path: &Path) -> String {
     let mut mime = "text/gemini".to_string();
     if path.is_dir() {
         return mime;
     }
-    let ext = match path.extension() {
-        Some(p) => p.to_str().unwrap(),
-        None => return "text/plain".to_string(),
-    };
+    let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    if let Some(m) = srv.server.mime.as_ref().and_then(|m| m.get(ext)) {
+        return m.clone();
+    }
 
     mime = match ext {
         "gemini" => mime,
@@ -42,15 +58,82 @@ fn get_mime(path: &PathBuf) -> String {
         _ => {
             match mime_guess::from_ext(ext).first() {
                 Some(m) => m.essence_str().to_string(),
-                None => "text/plain".to_string(),
+                None => {
+                    let sniffed = match srv.server.mime_sniff {
+                        Some(true) => util::sniff_mime(path),
+                        _ => None,
+                    };
+                    match (sniffed, &srv.server.mime_default) {
+                        (Some(m), _) => m.to_string(),
+                        (None, Some(d)) => d.clone(),
+                        (None, None) => "text/plain".to_string(),
+                    }
+                },
             }
         },
     };
 
-    return mime;
+    mime
+}
+
+fn get_charset(srv: &config::ServerCfg, cpath: &str, path: &Path) -> Option<String> {
+    if let Some(c) = srv.server.charsets.as_ref().and_then(|c| util::prefix_match(c, cpath)) {
+        return Some(c.clone());
+    }
+    if let Some(c) = &srv.server.charset {
+        return Some(c.clone());
+    }
+    if srv.server.charset_detect.unwrap_or(false) {
+        if let Ok(c) = util::detect_charset(path) {
+            return Some(c.to_string());
+        }
+    }
+    None
 }
 
-async fn get_binary(mut con: conn::Connection, path: PathBuf, meta: String) -> io::Result<()> {
+// Find the first of names in dir, preferring a variant in lang e.g.
+// index.fr.gmi over index.gmi. If only other languages exist use one of them.
+// Names that already have a language tag are only looked for as they are.
+fn find_variant(dir: &Path, names: &[String], lang: Option<&str>) -> Option<PathBuf> {
+    for n in names.iter() {
+        if let (Some(l), (stem, None, ext)) = (lang, util::split_lang(n)) {
+            let p = dir.join(format!("{}.{}.{}", stem, l, ext));
+            if p.exists() {
+                return Some(p);
+            }
+        }
+        let p = dir.join(n);
+        if p.exists() {
+            return Some(p);
+        }
+    }
+    for n in names.iter().filter(|n| util::split_lang(n).1.is_none()) {
+        if let Some((_, name)) = util::lang_variants(&dir.join(n)).into_iter().next() {
+            return Some(dir.join(name));
+        }
+    }
+    None
+}
+
+// A gemtext footer linking to the other language versions of path
+fn lang_hint(path: &Path, default: Option<&str>, clean: bool) -> Option<String> {
+    let variants = util::lang_variants(path);
+    if variants.is_empty() {
+        return None;
+    }
+    let mut hint = String::from("\r\n## Other languages\r\n\r\n");
+    for (lang, name) in variants {
+        let lang = lang.as_deref().or(default).unwrap_or("default");
+        let name = match util::clean_name(&name) {
+            Some(n) if clean => n,
+            _ => name,
+        };
+        hint.push_str(&format!("=> ./{} {}\r\n", name, lang));
+    }
+    Some(hint)
+}
+
+async fn get_binary(con: &mut conn::Connection, path: PathBuf, meta: String) -> io::Result<()> {
     let fd = File::open(path)?;
     let mut reader = BufReader::with_capacity(1024 * 1024, fd);
     con.send_status(status::Status::Success, Some(&meta))
@@ -69,55 +152,68 @@ async fn get_binary(mut con: conn::Connection, path: PathBuf, meta: String) -> i
     Ok(())
 }
 
-async fn get_content(path: PathBuf, u: url::Url) -> Result<String, io::Error> {
-    let meta = tokio::fs::metadata(&path).await?;
-    if meta.is_file() {
-        return Ok(tokio::fs::read_to_string(path).await?);
-    }
-
-    let mut dirs: Vec<String> = Vec::new();
-    let mut files: Vec<String> = Vec::new();
-
-    // needs work
-    for file in fs::read_dir(&path)? {
-        if let Ok(file) = file {
-            let m = file.metadata()?;
-            let perm = m.permissions();
-            if perm.mode() & 0o0444 != 0o0444 {
-                continue;
-            }
-            let file = file.path();
-            let p = file.strip_prefix(&path).unwrap();
-            let ps = match p.to_str() {
-                Some(s) => s,
-                None => continue,
-            };
-            let ep = match u.join(ps) {
-                Ok(p) => p,
-                _ => continue,
-            };
-            if m.is_dir() {
-                dirs.push(format!("=> {}/ {}/\r\n", ep, p.display()));
-            } else {
-                files.push(format!("=> {} {}\r\n", ep, p.display()));
-            }
+// Check if cpath is under maintenance and the client isn't allowed through.
+// Returns the message to send, if there's one.
+fn maintenance(
+    con: &conn::Connection,
+    srv: &config::ServerCfg,
+    m: &config::Maintenance,
+    cpath: &str,
+) -> Option<Option<String>> {
+    if let Some(p) = &m.paths {
+        if !p.iter().any(|p| cpath.starts_with(p.as_str())) {
+            return None;
         }
     }
-
-    dirs.sort();
-    files.sort();
-
-    let mut list = String::from("# Directory Listing\r\n\r\n");
-    list.push_str(&format!("Path: {}\r\n\r\n", u.path()));
-
-    for dir in dirs {
-        list.push_str(&dir);
+    let sentinel = srv.server.dir.as_ref()
+        .and_then(|d| fs::read_to_string(Path::new(d).join(".maintenance")).ok());
+    if !m.enabled.unwrap_or(false) && sentinel.is_none() {
+        return None;
     }
-    for file in files {
-        list.push_str(&file);
+    let allow = m.allow.as_deref().unwrap_or_default();
+    let hash = con.peer.cert.as_ref().map(|c| &c.hash);
+    let allowed = allow.iter().any(|a| {
+        Some(a) == hash || con.peer.ip().is_some_and(|ip| util::ip_in(ip, a))
+    });
+    if allowed {
+        return None;
     }
+    let msg = sentinel
+        .and_then(|s| s.lines().next().map(|l| l.trim().to_string()))
+        .filter(|l| !l.is_empty())
+        .or_else(|| m.message.clone());
+    Some(msg)
+}
 
-    return Ok(list);
+async fn send_fixed(con: &mut conn::Connection, r: &config::Response, request: &str) -> io::Result<()> {
+    con.record.handler = Some("fixed");
+    let stat = Status::from_u8(r.status).unwrap_or(Status::PermanentFailure);
+    let body = match (&r.body, &r.file) {
+        (Some(b), _) => Some(b.as_bytes().to_vec()),
+        (None, Some(f)) => match tokio::fs::read(f).await {
+            Ok(b) => Some(b),
+            Err(e) => {
+                log::error!("Can't read response file {}: {}", f, e);
+                logger::logger(con.peer.addr, Status::TemporaryFailure, request);
+                con.send_status(Status::TemporaryFailure, None).await?;
+                return Ok(());
+            }
+        },
+        (None, None) => None,
+    };
+    let meta = match (&r.meta, r.status / 10) {
+        (Some(m), _) => m.as_str(),
+        (None, 2) => "text/gemini",
+        (None, _) => stat.to_str(),
+    };
+    logger::logger(con.peer.addr, stat, request);
+    con.send_status(stat, Some(meta)).await?;
+    if let Some(b) = body {
+        if r.status / 10 == 2 {
+            con.send_raw(&b).await?;
+        }
+    }
+    Ok(())
 }
 
 // Handle CGI and return Ok(true), or indicate this request wasn't for CGI with Ok(false)
@@ -127,11 +223,12 @@ async fn handle_cgi(
     srv: &config::ServerCfg,
     request: &str,
     url: &Url,
+    cpath: &str,
     full_path: &PathBuf,
 ) -> Result<bool, io::Error> {
     if srv.server.cgi.unwrap_or(false) {
         let mut path = full_path.clone();
-        let mut segments = url.path_segments().unwrap();
+        let mut segments = cpath.split('/').filter(|s| !s.is_empty());
         let mut path_info = "".to_string();
 
         // Find an ancestor url that matches a file
@@ -152,10 +249,11 @@ async fn handle_cgi(
             Some(c) => {
             if path.starts_with(c) {
                 if perm.mode() & 0o0111 == 0o0111 {
-                    cgi::cgi(con, srv, path, url, script_name, path_info).await?;
+                    con.record.handler = Some("cgi");
+                    cgi::cgi(con, srv, path, url, request, script_name, path_info).await?;
                     return Ok(true);
                 } else {
-                    logger::logger(con.peer_addr, Status::CGIError, request);
+                    logger::logger(con.peer.addr, Status::CGIError, request);
                     con.send_status(Status::CGIError, None).await?;
                     return Ok(true);
                 }
@@ -163,7 +261,8 @@ async fn handle_cgi(
             },
             None => {
                 if meta.is_file() && perm.mode() & 0o0111 == 0o0111 {
-                    cgi::cgi(con, srv, path, url, script_name, path_info).await?;
+                    con.record.handler = Some("cgi");
+                    cgi::cgi(con, srv, path, url, request, script_name, path_info).await?;
                     return Ok(true);
                 }
             },
@@ -172,20 +271,67 @@ async fn handle_cgi(
     Ok(false)
 }
 
+// The access, maintenance and rate limit checks that depend on the path. They
+// run for the requested path and again for the one it's rewritten to so a
+// rewrite can't get around them. taken is the rate limit already charged, which
+// isn't charged twice. Returns true if the request was answered.
+async fn check_path(
+    con: &mut conn::Connection,
+    srv: &config::ServerCfg,
+    cpath: &str,
+    request: &str,
+    taken: &mut Option<String>,
+) -> io::Result<bool> {
+    let route = srv.server.accesses.as_ref().and_then(|a| util::prefix_match(a, cpath));
+    let levels = [srv.access.as_ref(), srv.server.access.as_ref(), route];
+    if let Some(a) = levels.iter().flatten().find(|a| !access::allowed(a, con.peer.ip())) {
+        if a.close.unwrap_or(false) {
+            logger::denied(con.peer.addr, request);
+            return Ok(true);
+        }
+        let stat = a.status.and_then(Status::from_u8).unwrap_or(Status::ProxyRequestRefused);
+        logger::logger(con.peer.addr, stat, request);
+        con.send_status(stat, a.meta.as_deref()).await?;
+        return Ok(true);
+    }
+
+    if let Some(m) = &srv.server.maintenance {
+        if let Some(msg) = maintenance(con, srv, m, cpath) {
+            logger::logger(con.peer.addr, Status::ServerUnavailable, request);
+            con.send_status(Status::ServerUnavailable, msg.as_deref()).await?;
+            return Ok(true);
+        }
+    }
+
+    let limit = match srv.server.ratelimits.as_ref().and_then(|r| util::prefix_match_entry(r, cpath)) {
+        Some((route, l)) => Some((format!("{}{}", srv.server.hostname, route), l)),
+        None => srv.server.ratelimit.as_ref().map(|l| (srv.server.hostname.clone(), l)),
+    };
+    if let Some((scope, l)) = limit.filter(|(scope, _)| taken.as_deref() != Some(scope.as_str())) {
+        let cert = con.peer.cert.as_ref().map(|c| c.hash.clone());
+        if let Err(wait) = srv.limiter.check(&scope, l, con.peer.ip(), cert) {
+            let secs = (wait.as_secs_f64().ceil() as u64).max(1).to_string();
+            logger::logger(con.peer.addr, Status::SlowDown, request);
+            con.send_status(Status::SlowDown, Some(&secs)).await?;
+            return Ok(true);
+        }
+        *taken = Some(scope);
+    }
+    Ok(false)
+}
+
 // TODO Rewrite this monster.
 async fn handle_connection(
-    mut con: conn::Connection,
+    con: &mut conn::Connection,
     srv: &config::ServerCfg,
+    vhosts: &HashMap<String, config::ServerCfg>,
 ) -> Result<(), io::Error> {
-    let index = match &srv.server.index {
-        Some(i) => i.clone(),
-        None => "index.gemini".to_string(),
-    };
     let mut buffer = [0; 1024];
-    let len = match tokio::time::timeout(tokio::time::Duration::from_secs(5), con.stream.read(&mut buffer)).await {
-        Ok(result) => result.unwrap(),
+    let len = match tokio::time::timeout(srv.timeouts("").read(), con.stream.read(&mut buffer)).await {
+        Ok(result) => result?,
         Err(_) => {
-            logger::logger(con.peer_addr, Status::BadRequest, "");
+            logger::timeout(con.peer.addr, "read", "");
+            logger::logger(con.peer.addr, Status::BadRequest, "");
             con.send_status(Status::BadRequest, None).await?;
             return Ok(());
         }
@@ -193,7 +339,7 @@ async fn handle_connection(
     let mut request = match String::from_utf8(buffer[..len].to_vec()) {
         Ok(request) => request,
         Err(_) => {
-            logger::logger(con.peer_addr, Status::BadRequest, "");
+            logger::logger(con.peer.addr, Status::BadRequest, "");
             con.send_status(Status::BadRequest, None).await?;
             return Ok(());
         }
@@ -208,18 +354,34 @@ async fn handle_connection(
             request.pop();
         }
     }
+    con.record.request = request.clone();
 
-    let url = match Url::parse(&request) {
+    let mut url = match Url::parse(&request) {
         Ok(url) => url,
         Err(_) => {
-            logger::logger(con.peer_addr, Status::BadRequest, &request);
+            logger::logger(con.peer.addr, Status::BadRequest, &request);
             con.send_status(Status::BadRequest, None).await?;
             return Ok(());
         }
     };
+    // Cleartext connections have no SNI so the vhost comes from the request
+    let srv = match url.host_str().and_then(|h| vhosts.get(h)) {
+        Some(s) if !con.peer.is_tls() => {
+            con.set_vhost(s);
+            s
+        }
+        _ => srv,
+    };
+    // Path rules are all matched against this, not the path as sent
+    let mut cpath = util::canonical_path(url.path());
+    con.write_timeout = srv.timeouts(&cpath).write();
 
-    if Some(srv.server.hostname.as_str()) != url.host_str() {
-        logger::logger(con.peer_addr, Status::ProxyRequestRefused, &request);
+    let alias = match (&srv.server.alias, url.host_str()) {
+        (Some(a), Some(h)) => a.iter().any(|a| a == h),
+        _ => false,
+    };
+    if Some(srv.server.hostname.as_str()) != url.host_str() && !alias {
+        logger::logger(con.peer.addr, Status::ProxyRequestRefused, &request);
         con.send_status(Status::ProxyRequestRefused, None).await?;
         return Ok(());
     }
@@ -227,7 +389,7 @@ async fn handle_connection(
     match url.port() {
         Some(p) => {
             if p != srv.port {
-                logger::logger(con.peer_addr, Status::ProxyRequestRefused, &request);
+                logger::logger(con.peer.addr, Status::ProxyRequestRefused, &request);
                 con.send_status(status::Status::ProxyRequestRefused, None)
                     .await?;
             }
@@ -236,18 +398,46 @@ async fn handle_connection(
     }
 
     if url.scheme() != "gemini" {
-        logger::logger(con.peer_addr, Status::ProxyRequestRefused, &request);
+        logger::logger(con.peer.addr, Status::ProxyRequestRefused, &request);
         con.send_status(Status::ProxyRequestRefused, None).await?;
         return Ok(());
     }
 
+    let mut taken = None;
+    if check_path(con, srv, &cpath, &request, &mut taken).await? {
+        return Ok(());
+    }
+
+    // Send everything for a moved vhost or an alias to the same path on the
+    // new host
+    let host = match &srv.server.redirect_host {
+        Some(h) => Some(h.as_str()),
+        None if alias => Some(srv.server.hostname.as_str()),
+        None => None,
+    };
+    if let Some(h) = host {
+        let mut u = url.clone();
+        let host_port: Vec<&str> = h.splitn(2, ':').collect();
+        // An alias is the same server so it keeps the requested port
+        let port = match host_port.get(1) {
+            Some(p) => p.parse().ok(),
+            None if srv.server.redirect_host.is_none() => url.port(),
+            None => None,
+        };
+        if u.set_host(Some(host_port[0])).is_ok() && u.set_port(port).is_ok() {
+            logger::logger(con.peer.addr, Status::RedirectPermanent, &request);
+            con.send_status(Status::RedirectPermanent, Some(u.as_str())).await?;
+            return Ok(());
+        }
+    }
+
     match &srv.server.redirect {
         Some(re) => {
-            let u = url.path().trim_end_matches("/");
-            match re.get(u) {
+            let u = cpath.trim_end_matches("/");
+            match re.get(u).and_then(|r| redirect::resolve(&url, r, false)) {
                 Some(r) => {
-                    logger::logger(con.peer_addr, Status::RedirectTemporary, &request);
-                    con.send_status(Status::RedirectTemporary, Some(r)).await?;
+                    logger::logger(con.peer.addr, Status::RedirectTemporary, &request);
+                    con.send_status(Status::RedirectTemporary, Some(&r)).await?;
                     return Ok(());
                 }
                 None => {}
@@ -256,6 +446,39 @@ async fn handle_connection(
         None => {}
     }
 
+    if let Some((stat, r)) = redirect::find(&srv.redirect_rules, &url) {
+        logger::logger(con.peer.addr, stat, &request);
+        con.send_status(stat, Some(&r)).await?;
+        return Ok(());
+    }
+
+    // Redirects and listings go to the client so they're built from the URL it
+    // asked for, not the one it was rewritten to
+    let requested = url.clone();
+    let cert = con.peer.cert.is_some();
+    if let Some(u) = rewrite::rewrite(&srv.rewrite, &url, cert) {
+        logger::rewrite(con.peer.addr, &request, &u);
+        url = u;
+    }
+
+    let fixed = srv.server.response.iter().flatten().find(|r| util::glob_match(&r.path, &cpath));
+    if let Some(r) = fixed {
+        match &r.forward {
+            Some(f) if url.query().is_some() => {
+                url.set_path(f);
+                logger::rewrite(con.peer.addr, &request, &url);
+            }
+            _ => {
+                send_fixed(con, r, &request).await?;
+                return Ok(());
+            }
+        }
+    }
+    let checked = std::mem::replace(&mut cpath, util::canonical_path(url.path()));
+    if cpath != checked && check_path(con, srv, &cpath, &request, &mut taken).await? {
+        return Ok(());
+    }
+
     #[cfg(feature = "proxy")]
     if let Some(pr) = &srv.server.proxy_all {
         let host_port: Vec<&str> = pr.splitn(2, ':').collect();
@@ -271,7 +494,9 @@ async fn handle_connection(
         upstream_url.set_host(Some(host)).unwrap();
         upstream_url.set_port(port).unwrap();
 
-        revproxy::proxy_all(pr, upstream_url, con).await?;
+        let timeout = srv.timeouts(&cpath).handler();
+        con.record.handler = Some("proxy_all");
+        revproxy::proxy_all(pr, upstream_url, con, timeout, srv.server.proxy_header).await?;
         return Ok(());
     }
 
@@ -280,7 +505,9 @@ async fn handle_connection(
         Some(pr) => match url.path_segments().map(|c| c.collect::<Vec<_>>()) {
             Some(s) => match pr.get(s[0]) {
                 Some(p) => {
-                    revproxy::proxy(p.to_string(), url, con).await?;
+                    let timeout = srv.timeouts(&cpath).handler();
+                    con.record.handler = Some("proxy");
+                    revproxy::proxy(p.to_string(), url, con, timeout, srv.server.proxy_header).await?;
                     return Ok(());
                 }
                 None => {}
@@ -293,10 +520,11 @@ async fn handle_connection(
     #[cfg(feature = "scgi")]
     match &srv.server.scgi {
         Some(sc) => {
-        let u = url.path().trim_end_matches("/");
+        let u = cpath.trim_end_matches("/");
         match sc.get(u) {
             Some(r) => {
-                cgi::scgi(r.to_string(), url, con, srv).await?;
+                con.record.handler = Some("scgi");
+                cgi::scgi(r.to_string(), url, &request, con, srv).await?;
                 return Ok(());
             }
             None => {}
@@ -305,38 +533,133 @@ async fn handle_connection(
         None => {},
     }
 
+    if let Some(gone) = &srv.server.gone {
+        let msg = gone
+            .iter()
+            .filter(|(g, _)| util::glob_match(g, &cpath))
+            .max_by_key(|(g, _)| g.len())
+            .map(|(_, m)| m);
+        if let Some(m) = msg {
+            let m = if m.is_empty() { None } else { Some(m.as_str()) };
+            logger::logger(con.peer.addr, Status::Gone, &request);
+            con.send_status(Status::Gone, m).await?;
+            return Ok(());
+        }
+    }
 
-    let mut path = PathBuf::new();
+    let mut root = PathBuf::new();
+    let mut path;
 
-    if url.path().starts_with("/~") && srv.server.usrdir.unwrap_or(false) {
-        let usr = url.path().trim_start_matches("/~");
+    if cpath.starts_with("/~") && srv.server.usrdir.unwrap_or(false) {
+        let usr = cpath.trim_start_matches("/~");
         let usr: Vec<&str> = usr.splitn(2, "/").collect();
         if cfg!(target_os = "macos") {
-            path.push("/Users/");
+            root.push("/Users/");
         } else {
-            path.push("/home/");
+            root.push("/home/");
         }
+        root.push(format!("{}/{}/", usr[0], "public_gemini"));
+        path = root.clone();
         if usr.len() == 2 {
-            path.push(format!("{}/{}/{}", usr[0], "public_gemini", util::url_decode(usr[1].as_bytes())));
-        } else {
-            path.push(format!("{}/{}/", usr[0], "public_gemini"));
+            path.push(usr[1]);
         }
     } else {
-        path.push(&srv.server.dir);
-        if url.path() != "" || url.path() != "/" {
-            let decoded = util::url_decode(url.path().trim_start_matches("/").as_bytes());
-            path.push(decoded);
+        match &srv.server.dir {
+            Some(d) => root.push(d),
+            None => {
+                logger::logger(con.peer.addr, Status::NotFound, &request);
+                con.send_status(Status::NotFound, None).await?;
+                return Ok(());
+            }
+        }
+        path = root.clone();
+        path.push(cpath.trim_start_matches("/"));
+    }
+
+    // With clean urls /page is served from page.gmi and /page.gmi redirects there
+    let clean = srv.server.clean_urls.unwrap_or(false);
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("").to_string();
+    if clean && !name.is_empty() && !url.path().ends_with("/") {
+        if !path.exists() || path.is_dir() {
+            let names = [format!("{}.gmi", name), format!("{}.gemini", name)];
+            let lang = srv.server.lang.as_deref();
+            if let Some(p) = path.parent().and_then(|d| find_variant(d, &names, lang)) {
+                path = p;
+            }
+        } else if srv.server.clean_redirect.unwrap_or(false) && path.is_file() && url.path() == requested.path() {
+            // Rewritten requests are never redirected since the file name is
+            // the rewrite's, not the client's
+            let target = if srv.index().contains(&name) {
+                Some("./".to_string())
+            } else {
+                util::clean_name(&name).map(|n| format!("./{}", n))
+            };
+            if let Some(mut u) = target.and_then(|t| requested.join(&t).ok()) {
+                u.set_query(requested.query());
+                logger::logger(con.peer.addr, Status::RedirectPermanent, &request);
+                con.send_status(Status::RedirectPermanent, Some(u.as_str())).await?;
+                return Ok(());
+            }
+        }
+    }
+
+    let mut dm = dirmeta::DirMeta::default();
+    if srv.server.dirmeta.unwrap_or(false) {
+        if path.file_name() == Some(dirmeta::FILE.as_ref()) {
+            logger::logger(con.peer.addr, Status::NotFound, &request);
+            con.send_status(Status::NotFound, None).await?;
+            return Ok(());
+        }
+        dm = dirmeta::lookup(&root, &path);
+        if dm.lang.is_some() {
+            con.lang = dm.lang.clone();
+        }
+        if dm.gone {
+            logger::logger(con.peer.addr, Status::Gone, &request);
+            con.send_status(Status::Gone, dm.meta.as_deref()).await?;
+            return Ok(());
+        }
+        if let Some((r, permanent)) = &dm.redirect {
+            let r = redirect::resolve(&url, r, false).unwrap_or_else(|| r.clone());
+            let stat = if *permanent {
+                Status::RedirectPermanent
+            } else {
+                Status::RedirectTemporary
+            };
+            logger::logger(con.peer.addr, stat, &request);
+            con.send_status(stat, Some(&r)).await?;
+            return Ok(());
         }
     }
 
     if !path.exists() {
         // See if it's a subpath of a CGI script before returning NotFound
         #[cfg(feature = "cgi")]
-        if handle_cgi(&mut con, srv, &request, &url, &path).await? {
+        if handle_cgi(con, srv, &request, &url, &cpath, &path).await? {
             return Ok(());
         }
 
-        logger::logger(con.peer_addr, Status::NotFound, &request);
+        // A tombstone like page.gmi.gone marks removed content. Its first
+        // line is an optional message.
+        if srv.server.gone_files.unwrap_or(false) {
+            let mut exts = vec![".gone"];
+            if clean {
+                exts.extend(&[".gmi.gone", ".gemini.gone"]);
+            }
+            for ext in exts {
+                let mut tomb = path.clone().into_os_string();
+                tomb.push(ext);
+                if let Ok(t) = tokio::fs::read_to_string(&tomb).await {
+                    let m = t.lines().next().unwrap_or("").trim();
+                    let m = if m.is_empty() { None } else { Some(m) };
+                    logger::logger(con.peer.addr, Status::Gone, &request);
+                    con.send_status(Status::Gone, m).await?;
+                    return Ok(());
+                }
+            }
+        }
+
+        logger::logger(con.peer.addr, Status::NotFound, &request);
         con.send_status(Status::NotFound, None).await?;
         return Ok(());
     }
@@ -347,17 +670,16 @@ async fn handle_connection(
     // TODO fix me
     // This block is terrible
     if meta.is_dir() {
-        if !url.path().ends_with("/") {
-            logger::logger(con.peer_addr, Status::RedirectPermanent, &request);
-            con.send_status(
-                Status::RedirectPermanent,
-                Some(format!("{}/", url).as_str()),
-            )
-            .await?;
+        if !url.path().ends_with("/") && !requested.path().ends_with("/") {
+            let mut u = requested.clone();
+            u.set_path(&format!("{}/", requested.path()));
+            logger::logger(con.peer.addr, Status::RedirectPermanent, &request);
+            con.send_status(Status::RedirectPermanent, Some(u.as_str())).await?;
             return Ok(());
         }
-        if path.join(&index).exists() {
-            path.push(index);
+        let lang = dm.lang.as_ref().or(srv.server.lang.as_ref());
+        if let Some(index) = find_variant(&path, &srv.index(), lang.map(|l| l.as_str())) {
+            path = index;
             meta = tokio::fs::metadata(&path).await?;
             perm = meta.permissions();
             if perm.mode() & 0o0444 != 0o444 {
@@ -371,39 +693,248 @@ async fn handle_connection(
     }
 
     #[cfg(feature = "cgi")]
-    if handle_cgi(&mut con, srv, &request, &url, &path).await? {
+    if handle_cgi(con, srv, &request, &url, &cpath, &path).await? {
         return Ok(());
     }
 
+    if srv.server.dirmeta.unwrap_or(false) && meta.is_file() {
+        dm = dirmeta::lookup(&root, &path);
+    }
+
     if meta.is_file() && perm.mode() & 0o0111 == 0o0111  {
-        logger::logger(con.peer_addr, Status::NotFound, &request);
+        logger::logger(con.peer.addr, Status::NotFound, &request);
         con.send_status(Status::NotFound, None).await?;
         return Ok(());
     }
 
     if perm.mode() & 0o0444 != 0o0444  {
-        logger::logger(con.peer_addr, Status::NotFound, &request);
+        logger::logger(con.peer.addr, Status::NotFound, &request);
         con.send_status(Status::NotFound, None).await?;
         return Ok(());
     }
 
-    let mut mime = get_mime(&path);
-    if mime == "text/gemini" && srv.server.lang.is_some() {
-        mime += &("; lang=".to_string() + &srv.server.lang.to_owned().unwrap());
+    let mut mime = dm.mime.clone().unwrap_or_else(|| get_mime(srv, &path));
+    if mime.starts_with("text/") {
+        // Directory listings are generated by us and always UTF-8
+        let charset = match meta.is_file() {
+            true => dm.charset.clone().or_else(|| get_charset(srv, &cpath, &path)),
+            false => Some("utf-8".to_string()),
+        };
+        if let Some(charset) = charset {
+            mime += &format!("; charset={}", charset);
+        }
     }
-    if !mime.starts_with("text/") {
-        logger::logger(con.peer_addr, Status::Success, &request);
-        get_binary(con, path, mime).await?;
+    let mut hint = None;
+    if mime.starts_with("text/gemini") {
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        let lang = util::split_lang(name).1.map(|l| l.to_string())
+            .or_else(|| dm.lang.clone())
+            .or_else(|| srv.server.lang.clone());
+        if let Some(lang) = &lang {
+            mime += &("; lang=".to_string() + lang);
+        }
+        if srv.server.lang_hint.unwrap_or(false) && meta.is_file() {
+            let default = dm.lang.as_ref().or(srv.server.lang.as_ref());
+            hint = lang_hint(&path, default.map(|l| l.as_str()), clean);
+        }
+    }
+    if let Some(m) = dm.meta {
+        mime = m;
+    }
+    if meta.is_file() {
+        con.record.handler = Some("file");
+        logger::logger(con.peer.addr, Status::Success, &request);
+        match &srv.cache {
+            Some(c) if meta.len() <= c.max_file_size => {
+                let key = path.to_string_lossy();
+                let body = match c.get(&key, &meta) {
+                    Some(b) => b,
+                    None => c.insert(&key, &meta, tokio::fs::read(&path).await?),
+                };
+                con.send_status(Status::Success, Some(&mime)).await?;
+                con.send_raw(&body).await?;
+            }
+            _ => get_binary(con, path, mime).await?,
+        }
+        if let Some(h) = hint {
+            con.send_raw(h.as_bytes()).await?;
+        }
         return Ok(());
     }
-    let content = get_content(path, url).await?;
-    con.send_body(status::Status::Success, Some(&mime), Some(content))
-        .await?;
-    logger::logger(con.peer_addr, Status::Success, &request);
+    let list = srv.dirlist(&cpath);
+    if !list.enabled.unwrap_or(true) {
+        logger::logger(con.peer.addr, Status::NotFound, &request);
+        con.send_status(Status::NotFound, None).await?;
+        return Ok(());
+    }
+    con.record.handler = Some("dir");
+    match &srv.cache {
+        // Keyed by vhost and directory so extra queries don't each get an
+        // entry and vhosts sharing a directory keep their own settings. Links
+        // in the listing follow the requested URL so rewritten requests aren't
+        // cached.
+        Some(c) if dirlist::is_static(&list) && url.path() == requested.path() => {
+            let key = format!("{} {}", srv.server.hostname, path.display());
+            let body = match c.get(&key, &meta) {
+                Some(b) => b,
+                None => {
+                    let content = dirlist::listing(&path, &requested, &list, clean).await?;
+                    c.insert(&key, &meta, content.into_bytes())
+                }
+            };
+            con.send_status(Status::Success, Some(&mime)).await?;
+            con.send_raw(&body).await?;
+        }
+        _ => {
+            let content = dirlist::listing(&path, &requested, &list, clean).await?;
+            con.send_body(status::Status::Success, Some(&mime), Some(content))
+                .await?;
+        }
+    }
+    logger::logger(con.peer.addr, Status::Success, &request);
 
     Ok(())
 }
 
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+// What every connection needs, whichever listener it came in on
+struct Shared {
+    acceptor: SslAcceptor,
+    cmap: Arc<HashMap<String, config::ServerCfg>>,
+    default: String,
+    bans: Option<Arc<ban::Bans>>,
+    conns: Arc<connlimit::Counter>,
+    refusal: Option<(Status, String)>,
+    read_timeout: Duration,
+    access_logs: accesslog::AccessLogs,
+    ids: accesslog::Ids,
+}
+
+// Do the TLS handshake and note what it tells about the client
+async fn handshake(sh: &Shared, stream: conn::Stream, peer: &mut conn::PeerInfo) -> io::Result<conn::Stream> {
+    match tokio::time::timeout(sh.read_timeout, tokio_openssl::accept(&sh.acceptor, stream)).await {
+        Ok(Ok(s)) => {
+            peer.set_tls(s.ssl());
+            Ok(Box::new(s))
+        }
+        Ok(Err(e)) => Err(io::Error::other(e.to_string())),
+        Err(_) => Err(io::Error::from(io::ErrorKind::TimedOut)),
+    }
+}
+
+// Set up a new connection and handle its request
+async fn serve(
+    sh: Arc<Shared>,
+    l: Arc<config::Listen>,
+    mut stream: conn::Stream,
+    mut peer_addr: SocketAddr,
+    mut local_addr: SocketAddr,
+) -> io::Result<()> {
+    let started = Instant::now();
+    if l.kind() == "tcp" && !l.trusts(peer_addr.ip()) {
+        log::warn!("remote={} untrusted", peer_addr);
+        return Ok(());
+    }
+    let mut header = None;
+    if l.proxy_protocol.unwrap_or(false) && (l.kind() == "unix" || l.trusts(peer_addr.ip())) {
+        match tokio::time::timeout(sh.read_timeout, proxyproto::read(&mut stream)).await {
+            Ok(Ok(Some(h))) => {
+                peer_addr = h.src;
+                local_addr = h.dst;
+                header = Some(h);
+            }
+            Ok(Ok(None)) => {}
+            Ok(Err(e)) => {
+                log::error!("remote={} {}", peer_addr, e);
+                return Ok(());
+            }
+            Err(_) => {
+                logger::timeout(peer_addr, "read", "");
+                return Ok(());
+            }
+        }
+    }
+    let mut peer = conn::PeerInfo::new(peer_addr, local_addr);
+    let bans = sh.bans.as_ref().filter(|_| peer.ip().is_some());
+    if bans.is_some_and(|b| b.banned(peer_addr.ip())) {
+        return Ok(());
+    }
+    if let (Some(h), Some(t)) = (&header, l.cert_tlv) {
+        peer.cert = h.tlv(t).map(|hash| conn::ClientCert {
+            hash: String::from_utf8_lossy(hash).into_owned(),
+            name: h.ssl_cn(),
+        });
+    }
+    let tls = l.kind() == "tls";
+    let slot = match sh.conns.open(peer.ip()) {
+        Some(s) => s,
+        None => {
+            logger::limit(peer_addr, sh.conns.total());
+            if let Some((stat, meta)) = &sh.refusal {
+                // Answer without reading the request
+                let stream = match tls {
+                    true => handshake(&sh, stream, &mut peer).await,
+                    false => Ok(stream),
+                };
+                if let Ok(mut s) = stream {
+                    let _ = s.write_all(format!("{} {}\r\n", *stat as u8, meta).as_bytes()).await;
+                    let _ = s.shutdown().await;
+                }
+            }
+            return Ok(());
+        }
+    };
+
+    if tls {
+        stream = match handshake(&sh, stream, &mut peer).await {
+            Ok(s) => s,
+            Err(e) => {
+                log::error!("Error: {}", e);
+                if let Some(b) = bans.filter(|b| b.counts_tls()) {
+                    b.strike(peer_addr.ip(), "tls");
+                }
+                return Ok(());
+            }
+        };
+    }
+
+    let srv = match peer.sni.as_ref().and_then(|s| sh.cmap.get(s)) {
+        Some(s) => s,
+        None => sh.cmap.get(&sh.default).unwrap(),
+    };
+
+    let mut con = conn::Connection::new(stream, peer);
+    con.set_vhost(srv);
+    con.record.id = sh.ids.next();
+    con.record.listener = match (&l.addr, &l.path) {
+        (_, Some(p)) => p.clone(),
+        (Some(a), None) => a.clone(),
+        (None, None) => String::new(),
+    };
+    let res = match srv.timeouts("").lifetime() {
+        Some(t) => match tokio::time::timeout(t, handle_connection(&mut con, srv, &sh.cmap)).await {
+            Ok(r) => r,
+            Err(_) => {
+                logger::timeout(peer_addr, "lifetime", "");
+                Ok(())
+            }
+        },
+        None => handle_connection(&mut con, srv, &sh.cmap).await,
+    };
+    drop(slot);
+    if let Some(log) = sh.access_logs.get(&con.record.vhost) {
+        log.write(&con, started.elapsed());
+    }
+    if let (Some(b), Some(stat)) = (bans, con.status) {
+        if b.counts_status(stat as u8) {
+            b.strike(peer_addr.ip(), &(stat as u8).to_string());
+        }
+    }
+    res
+}
+
 fn main() -> io::Result<()> {
     let args: Vec<String> = env::args().collect();
     if args.len() != 2 {
@@ -437,15 +968,32 @@ fn main() -> io::Result<()> {
             }
         },
     };
-    simple_logger::init_with_level(loglev).unwrap();
-    let cmap = cfg.to_map();
+    let mut files = logfile::Files::default();
+    match logfile::Logger::new(&cfg, loglev, &mut files) {
+        Ok(Some(l)) => l.init().unwrap(),
+        Ok(None) => simple_logger::init_with_level(loglev).unwrap(),
+        Err(e) => {
+            eprintln!("Config error: {}", e);
+            return Ok(());
+        },
+    }
+    let cmap = match cfg.to_map() {
+        Ok(c) => Arc::new(c),
+        Err(e) => {
+            eprintln!("Config error: {}", e);
+            return Ok(());
+        },
+    };
     let default = &cfg.server[0].hostname;
     println!("Serving {} vhosts", cfg.server.len());
 
-    let addr = format!("{}:{}", cfg.host, cfg.port);
-    addr.to_socket_addrs()?
-        .next()
-        .ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))?;
+    let listeners = match cfg.listeners() {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("Config error: {}", e);
+            return Ok(());
+        },
+    };
 
     let mut runtime = runtime::Builder::new()
         .threaded_scheduler()
@@ -457,40 +1005,132 @@ fn main() -> io::Result<()> {
 
     let acceptor = tls::acceptor_conf(cfg.clone())?;
 
-    let fut = async {
-        let mut listener = TcpListener::bind(&addr).await?;
-        loop {
-            let (stream, peer_addr) = listener.accept().await?;
-            let acceptor = acceptor.clone();
-            let cmap = cmap.clone();
-            let default = default.clone();
-
-            let fut = async move {
-                let stream = match tokio_openssl::accept(&acceptor, stream).await {
-                    Ok(s) => s,
-                    Err(e) => {
-                        log::error!("Error: {}",e);
-                        return Ok(());
-                    },
-                };
-
-                let srv = match stream.ssl().servername(NameType::HOST_NAME) {
-                    Some(s) => match cmap.get(s) {
-                        Some(ss) => ss,
-                        None => cmap.get(&default).unwrap(),
-                    },
-                    None => cmap.get(&default).unwrap(),
-                };
+    let bans = cfg.ban.as_ref().map(|b| Arc::new(ban::Bans::new(b)));
+    let conns = Arc::new(connlimit::Counter::new(cfg.connections.as_ref()));
+    let refusal = cfg.connections.as_ref().and_then(|c| {
+        let stat = Status::from_u8(c.status?)?;
+        let meta = match (&c.meta, stat) {
+            (Some(m), _) => m.clone(),
+            (None, Status::SlowDown) => "10".to_string(),
+            (None, _) => stat.to_str().to_string(),
+        };
+        Some((stat, meta))
+    });
+    let access_logs = match accesslog::AccessLogs::new(&cfg, &mut files) {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("Config error: {}", e);
+            return Ok(());
+        },
+    };
+    // Installed before any connection is taken so an early SIGUSR1 can't end
+    // the server
+    let reopen = handle.enter(|| files.reopen_on_signal())?;
+    handle.spawn(reopen);
+    if let Some(path) = cfg.admin.clone() {
+        handle.spawn(admin::serve(path, bans.clone(), conns.clone()));
+    }
 
-                let con = conn::Connection { stream, peer_addr };
-                handle_connection(con, srv).await?;
+    let cache = cmap.get(default).and_then(|s| s.cache.clone());
+    let stats = conns.clone();
+    handle.spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(300));
+        loop {
+            interval.tick().await;
+            log::info!("connections={}", stats.total());
+            if let Some(cache) = &cache {
+                log::info!("cache {}", cache.stats());
+            }
+        }
+    });
 
-                Ok(()) as io::Result<()>
-            };
+    let shared = Arc::new(Shared {
+        acceptor,
+        cmap,
+        default: default.clone(),
+        bans,
+        conns,
+        refusal,
+        read_timeout: cfg.timeout.clone().unwrap_or_default().read(),
+        access_logs,
+        ids: accesslog::Ids::new(),
+    });
 
-            handle.spawn(fut.unwrap_or_else(|err| eprintln!("{:?}", err)));
+    let fut = async {
+        for l in listeners {
+            let mut listener = listener::Listener::bind(&l).await?;
+            let l = Arc::new(l);
+            let shared = shared.clone();
+            let handle2 = handle.clone();
+            handle.spawn(async move {
+                let mut backoff = ACCEPT_BACKOFF_MIN;
+                loop {
+                    // Errors like running out of file descriptors pass, so
+                    // wait and try again instead of giving up.
+                    let (stream, peer_addr, local_addr) = match listener.accept().await {
+                        Ok(a) => {
+                            backoff = ACCEPT_BACKOFF_MIN;
+                            a
+                        }
+                        Err(e) => {
+                            log::error!("Accept failed: {}", e);
+                            tokio::time::delay_for(backoff).await;
+                            backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                            continue;
+                        }
+                    };
+                    let fut = logfile::scope(serve(shared.clone(), l.clone(), stream, peer_addr, local_addr));
+                    handle2.spawn(fut.unwrap_or_else(|err| eprintln!("{:?}", err)));
+                }
+            });
         }
+        future::pending::<()>().await;
+        Ok(())
     };
 
     runtime.block_on(fut)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use conn::tests::{connection, run};
+
+    const CONFIG: &str = r#"
+        port = 1965
+        host = "127.0.0.1"
+
+        [[server]]
+        hostname = "example.com"
+        dir = "/nonexistent"
+        key = "key.pem"
+        cert = "cert.pem"
+    "#;
+
+    // What the server answers to input on a fresh connection
+    fn answer(input: &[u8]) -> String {
+        let cfg: config::Config = toml::from_str(CONFIG).unwrap();
+        let vhosts = cfg.to_map().unwrap();
+        let srv = &vhosts["example.com"];
+        let (mut con, out) = connection(input);
+        con.set_vhost(srv);
+        run(handle_connection(&mut con, srv, &vhosts)).unwrap();
+        let out = out.lock().unwrap();
+        String::from_utf8_lossy(&out).into_owned()
+    }
+
+    #[test]
+    fn non_utf8_request() {
+        assert_eq!(answer(b"gemini://example.com/\xff\r\n"), "59 Bad Request!\r\n");
+    }
+
+    #[test]
+    fn foreign_host() {
+        assert!(answer(b"gemini://other.example/\r\n").starts_with("53 "));
+    }
+
+    #[test]
+    fn missing_file() {
+        assert!(answer(b"gemini://example.com/nope.gmi\r\n").starts_with("51 "));
+    }
+}
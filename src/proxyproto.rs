@@ -0,0 +1,376 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIG: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+// The longest a version 1 header can be
+const V1_MAX: usize = 107;
+
+// The client and the address it connected to as seen by the proxy. tlvs are
+// the extra fields of a version 2 header as type and value.
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    pub tlvs: Vec<(u8, Vec<u8>)>,
+}
+
+// The TLS type and its client certificate common name subtype
+const PP2_TYPE_SSL: u8 = 0x20;
+const PP2_SUBTYPE_SSL_CN: u8 = 0x22;
+
+impl Header {
+    pub fn tlv(&self, kind: u8) -> Option<&[u8]> {
+        self.tlvs.iter().find(|(k, _)| *k == kind).map(|(_, v)| v.as_slice())
+    }
+
+    // The common name of the client certificate the proxy got, if any
+    pub fn ssl_cn(&self) -> Option<String> {
+        let ssl = self.tlv(PP2_TYPE_SSL)?;
+        // client and verify fields come before the subtypes
+        let sub = tlvs(ssl.get(5..)?);
+        let cn = sub.iter().find(|(k, _)| *k == PP2_SUBTYPE_SSL_CN)?;
+        String::from_utf8(cn.1.clone()).ok()
+    }
+}
+
+fn tlvs(mut b: &[u8]) -> Vec<(u8, Vec<u8>)> {
+    let mut out = Vec::new();
+    while b.len() >= 3 {
+        let len = u16::from_be_bytes([b[1], b[2]]) as usize;
+        if b.len() < 3 + len {
+            break;
+        }
+        out.push((b[0], b[3..3 + len].to_vec()));
+        b = &b[3 + len..];
+    }
+    out
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("PROXY header: {}", what))
+}
+
+// Read a version 1 or 2 PROXY header without reading past it. Returns None
+// for connections the proxy made itself, like health checks.
+pub async fn read<S: AsyncRead + Unpin>(s: &mut S) -> io::Result<Option<Header>> {
+    // Both versions are at least this long, so this never eats into the
+    // data that follows
+    let mut start = [0u8; 12];
+    s.read_exact(&mut start).await?;
+    if &start == V2_SIG {
+        read_v2(s).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(s, &start).await
+    } else {
+        Err(invalid("missing"))
+    }
+}
+
+async fn read_v1<S: AsyncRead + Unpin>(s: &mut S, start: &[u8]) -> io::Result<Option<Header>> {
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX {
+            return Err(invalid("line too long"));
+        }
+        line.push(s.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("not text"))?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.get(1) {
+        Some(&"UNKNOWN") => return Ok(None),
+        Some(&"TCP4") | Some(&"TCP6") if parts.len() == 6 => {}
+        _ => return Err(invalid("bad line")),
+    }
+    let addr = |ip: &str, port: &str| -> io::Result<SocketAddr> {
+        let ip = ip.parse::<IpAddr>().map_err(|_| invalid("bad address"))?;
+        let port = port.parse::<u16>().map_err(|_| invalid("bad port"))?;
+        Ok(SocketAddr::new(ip, port))
+    };
+    Ok(Some(Header {
+        src: addr(parts[2], parts[4])?,
+        dst: addr(parts[3], parts[5])?,
+        tlvs: Vec::new(),
+    }))
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(s: &mut S) -> io::Result<Option<Header>> {
+    let mut head = [0u8; 4];
+    s.read_exact(&mut head).await?;
+    if head[0] >> 4 != 2 {
+        return Err(invalid("unknown version"));
+    }
+    let len = u16::from_be_bytes([head[2], head[3]]) as usize;
+    let mut body = vec![0u8; len];
+    s.read_exact(&mut body).await?;
+    match head[0] & 0xf {
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("unknown command")),
+    }
+    let port = |b: &[u8]| u16::from_be_bytes([b[0], b[1]]);
+    match head[1] {
+        0x11 if len >= 12 => {
+            let ip = |b: &[u8]| IpAddr::V4(Ipv4Addr::new(b[0], b[1], b[2], b[3]));
+            Ok(Some(Header {
+                src: SocketAddr::new(ip(&body[0..4]), port(&body[8..10])),
+                dst: SocketAddr::new(ip(&body[4..8]), port(&body[10..12])),
+                tlvs: tlvs(&body[12..]),
+            }))
+        }
+        0x21 if len >= 36 => {
+            let ip = |b: &[u8]| {
+                let mut a = [0u8; 16];
+                a.copy_from_slice(b);
+                IpAddr::V6(Ipv6Addr::from(a))
+            };
+            Ok(Some(Header {
+                src: SocketAddr::new(ip(&body[0..16]), port(&body[32..34])),
+                dst: SocketAddr::new(ip(&body[16..32]), port(&body[34..36])),
+                tlvs: tlvs(&body[36..]),
+            }))
+        }
+        0x11 | 0x21 => Err(invalid("short address")),
+        // Unix sockets and unspecified protocols carry no usable address
+        _ => Ok(None),
+    }
+}
+
+fn as_v6(a: SocketAddr) -> SocketAddr {
+    match a.ip() {
+        IpAddr::V4(v4) => SocketAddr::new(IpAddr::V6(v4.to_ipv6_mapped()), a.port()),
+        IpAddr::V6(_) => a,
+    }
+}
+
+// A version 1 or 2 header telling an upstream about the client
+pub fn encode(version: u8, h: &Header) -> Vec<u8> {
+    let (src, dst) = match (h.src, h.dst) {
+        (SocketAddr::V4(_), SocketAddr::V4(_)) => (h.src, h.dst),
+        _ => (as_v6(h.src), as_v6(h.dst)),
+    };
+    if version == 1 {
+        let proto = if src.is_ipv4() { "TCP4" } else { "TCP6" };
+        return format!(
+            "PROXY {} {} {} {} {}\r\n",
+            proto, src.ip(), dst.ip(), src.port(), dst.port()
+        )
+        .into_bytes();
+    }
+    let mut out = V2_SIG.to_vec();
+    out.push(0x21);
+    let mut addrs = Vec::new();
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            out.push(0x11);
+            addrs.extend_from_slice(&s.octets());
+            addrs.extend_from_slice(&d.octets());
+        }
+        (s, d) => {
+            out.push(0x21);
+            for ip in [s, d].iter() {
+                if let IpAddr::V6(v6) = ip {
+                    addrs.extend_from_slice(&v6.octets());
+                }
+            }
+        }
+    }
+    addrs.extend_from_slice(&src.port().to_be_bytes());
+    addrs.extend_from_slice(&dst.port().to_be_bytes());
+    out.extend_from_slice(&(addrs.len() as u16).to_be_bytes());
+    out.extend_from_slice(&addrs);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(b: &[u8]) -> (io::Result<Option<Header>>, Vec<u8>) {
+        let mut s = b;
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().build().unwrap();
+        let h = rt.block_on(read(&mut s));
+        (h, s.to_vec())
+    }
+
+    fn v2(cmd: u8, fam: u8, body: &[u8]) -> Vec<u8> {
+        let mut b = V2_SIG.to_vec();
+        b.push(0x20 | cmd);
+        b.push(fam);
+        b.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        b.extend_from_slice(body);
+        b
+    }
+
+    fn tlv(kind: u8, v: &[u8]) -> Vec<u8> {
+        let mut b = vec![kind];
+        b.extend_from_slice(&(v.len() as u16).to_be_bytes());
+        b.extend_from_slice(v);
+        b
+    }
+
+    #[test]
+    fn v1_tcp4() {
+        let (h, rest) = parse(b"PROXY TCP4 192.0.2.1 198.51.100.2 5000 1965\r\nhello");
+        let h = h.unwrap().unwrap();
+        assert_eq!(h.src, "192.0.2.1:5000".parse().unwrap());
+        assert_eq!(h.dst, "198.51.100.2:1965".parse().unwrap());
+        assert!(h.tlvs.is_empty());
+        assert_eq!(rest, b"hello");
+    }
+
+    #[test]
+    fn v1_tcp6() {
+        let (h, rest) = parse(b"PROXY TCP6 2001:db8::1 2001:db8::2 5000 1965\r\n");
+        let h = h.unwrap().unwrap();
+        assert_eq!(h.src, "[2001:db8::1]:5000".parse().unwrap());
+        assert_eq!(h.dst, "[2001:db8::2]:1965".parse().unwrap());
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn v1_unknown() {
+        let (h, rest) = parse(b"PROXY UNKNOWN\r\nhello");
+        assert!(h.unwrap().is_none());
+        assert_eq!(rest, b"hello");
+    }
+
+    #[test]
+    fn v1_bad() {
+        let long = format!("PROXY TCP4 {}\r\n", "1".repeat(V1_MAX));
+        let bad = [
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 5000\r\n".to_vec(),
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 5000 99999\r\n".to_vec(),
+            b"PROXY TCP4 example 198.51.100.2 5000 1965\r\n".to_vec(),
+            b"PROXY UDP4 192.0.2.1 198.51.100.2 5000 1965\r\n".to_vec(),
+            b"PROXY TCP4 192.0.2.1".to_vec(),
+            long.into_bytes(),
+        ];
+        for b in bad.iter() {
+            assert!(parse(b).0.is_err(), "{:?}", String::from_utf8_lossy(b));
+        }
+    }
+
+    #[test]
+    fn missing() {
+        assert!(parse(b"gemini://example.com/\r\n").0.is_err());
+        assert!(parse(b"PROX").0.is_err());
+        assert!(parse(b"").0.is_err());
+    }
+
+    #[test]
+    fn v2_proxy_ipv4() {
+        let mut ssl = vec![0x01, 0, 0, 0, 0];
+        ssl.extend(tlv(PP2_SUBTYPE_SSL_CN, b"alice"));
+        let mut body = vec![192, 0, 2, 1, 198, 51, 100, 2, 0x13, 0x88, 0x07, 0xad];
+        body.extend(tlv(PP2_TYPE_SSL, &ssl));
+        body.extend(tlv(0xE0, b"abcd"));
+        let mut b = v2(1, 0x11, &body);
+        b.extend_from_slice(b"hello");
+        let (h, rest) = parse(&b);
+        let h = h.unwrap().unwrap();
+        assert_eq!(h.src, "192.0.2.1:5000".parse().unwrap());
+        assert_eq!(h.dst, "198.51.100.2:1965".parse().unwrap());
+        assert_eq!(h.tlv(0xE0), Some(&b"abcd"[..]));
+        assert_eq!(h.tlv(0xE1), None);
+        assert_eq!(h.ssl_cn().as_deref(), Some("alice"));
+        assert_eq!(rest, b"hello");
+    }
+
+    #[test]
+    fn v2_proxy_ipv6() {
+        let mut body = Vec::new();
+        body.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        body.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        body.extend_from_slice(&[0x13, 0x88, 0x07, 0xad]);
+        let h = parse(&v2(1, 0x21, &body)).0.unwrap().unwrap();
+        assert_eq!(h.src, "[2001:db8::1]:5000".parse().unwrap());
+        assert_eq!(h.dst, "[2001:db8::2]:1965".parse().unwrap());
+    }
+
+    #[test]
+    fn v2_local_and_unspecified() {
+        let (h, rest) = parse(&[v2(0, 0x00, &[]), b"hello".to_vec()].concat());
+        assert!(h.unwrap().is_none());
+        assert_eq!(rest, b"hello");
+        // Unix socket addresses carry nothing usable
+        assert!(parse(&v2(1, 0x31, &[0; 216])).0.unwrap().is_none());
+    }
+
+    #[test]
+    fn v2_bad() {
+        let mut wrong_version = v2(1, 0x11, &[0; 12]);
+        wrong_version[12] = 0x11;
+        assert!(parse(&wrong_version).0.is_err());
+        assert!(parse(&v2(2, 0x11, &[0; 12])).0.is_err());
+        // Declares more than it sends
+        let mut short = v2(1, 0x11, &[0; 12]);
+        short.truncate(20);
+        assert!(parse(&short).0.is_err());
+        assert!(parse(&V2_SIG[..]).0.is_err());
+        // Too short for the addresses it claims
+        assert!(parse(&v2(1, 0x11, &[0; 6])).0.is_err());
+        assert!(parse(&v2(1, 0x21, &[0; 20])).0.is_err());
+    }
+
+    #[test]
+    fn tlv_parsing() {
+        let mut b = tlv(1, b"a");
+        b.extend(tlv(2, b""));
+        b.extend(tlv(3, b"bc"));
+        assert_eq!(tlvs(&b), vec![(1, b"a".to_vec()), (2, Vec::new()), (3, b"bc".to_vec())]);
+        // A value running past the end is dropped along with anything after
+        b.extend_from_slice(&[4, 0, 9, 1]);
+        assert_eq!(tlvs(&b).len(), 3);
+        assert!(tlvs(&[1, 0]).is_empty());
+    }
+
+    #[test]
+    fn ssl_cn_missing() {
+        let h = Header {
+            src: "192.0.2.1:1".parse().unwrap(),
+            dst: "192.0.2.2:2".parse().unwrap(),
+            tlvs: vec![(PP2_TYPE_SSL, vec![1, 0])],
+        };
+        assert_eq!(h.ssl_cn(), None);
+    }
+
+    #[test]
+    fn round_trip() {
+        let pairs = [
+            ("192.0.2.1:5000", "198.51.100.2:1965"),
+            ("[2001:db8::1]:5000", "[2001:db8::2]:1965"),
+        ];
+        for (src, dst) in pairs.iter() {
+            let h = Header {
+                src: src.parse().unwrap(),
+                dst: dst.parse().unwrap(),
+                tlvs: Vec::new(),
+            };
+            for version in [1, 2].iter() {
+                let mut b = encode(*version, &h);
+                b.extend_from_slice(b"hello");
+                let (got, rest) = parse(&b);
+                let got = got.unwrap().unwrap();
+                assert_eq!((got.src, got.dst), (h.src, h.dst));
+                assert_eq!(rest, b"hello");
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_mixed() {
+        // An IPv4 client that connected over IPv6 goes out as mapped addresses
+        let h = Header {
+            src: "192.0.2.1:5000".parse().unwrap(),
+            dst: "[2001:db8::2]:1965".parse().unwrap(),
+            tlvs: Vec::new(),
+        };
+        for version in [1, 2].iter() {
+            let got = parse(&encode(*version, &h)).0.unwrap().unwrap();
+            assert_eq!(got.src, "[::ffff:192.0.2.1]:5000".parse().unwrap());
+            assert_eq!(got.dst, h.dst);
+        }
+    }
+}
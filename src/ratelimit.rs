@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config;
+use crate::util;
+
+const DEFAULT_ENTRIES: usize = 10000;
+
+pub fn validate(l: &config::RateLimit) -> io::Result<()> {
+    let err = |what: String| Err(io::Error::new(io::ErrorKind::InvalidInput, what));
+    if !l.rate.is_finite() || l.rate <= 0.0 {
+        return err(format!("ratelimit has invalid rate {}", l.rate));
+    }
+    if let Some(b) = l.burst.filter(|b| !b.is_finite() || *b <= 0.0) {
+        return err(format!("ratelimit has invalid burst {}", b));
+    }
+    if let Some(k) = l.key.as_deref().filter(|k| *k != "ip" && *k != "cert") {
+        return err(format!("ratelimit has unknown key {}", k));
+    }
+    if let Some(p) = l.prefix6.filter(|p| *p > 128) {
+        return err(format!("ratelimit has invalid prefix6 {}", p));
+    }
+    Ok(())
+}
+
+// A token bucket holding up to burst tokens and refilling at rate per second
+#[derive(Debug, Clone)]
+pub struct Bucket {
+    tokens: f64,
+    last: Instant,
+    // When tokens were last taken, which refilling doesn't change
+    used: Instant,
+}
+
+impl Bucket {
+    pub fn new(burst: f64) -> Bucket {
+        let now = Instant::now();
+        Bucket {
+            tokens: burst,
+            last: now,
+            used: now,
+        }
+    }
+
+    fn refill(&mut self, rate: f64, burst: f64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.last = now;
+    }
+
+    // Take n tokens, or return how long until there would be enough.
+    pub fn take(&mut self, rate: f64, burst: f64, n: f64) -> Result<(), Duration> {
+        self.refill(rate, burst);
+        self.used = self.last;
+        if self.tokens >= n {
+            self.tokens -= n;
+            return Ok(());
+        }
+        Err(Duration::try_from_secs_f64((n - self.tokens) / rate).unwrap_or(Duration::MAX))
+    }
+
+    // Take n tokens even if that leaves the bucket in debt, and return how
+    // long until it's out of debt again.
+    pub fn reserve(&mut self, rate: f64, burst: f64, n: f64) -> Duration {
+        self.refill(rate, burst);
+        self.tokens -= n;
+        if self.tokens >= 0.0 || rate <= 0.0 {
+            return Duration::from_secs(0);
+        }
+        Duration::from_secs_f64(-self.tokens / rate)
+    }
+
+    fn full(&self, rate: f64, burst: f64) -> bool {
+        self.tokens + self.last.elapsed().as_secs_f64() * rate >= burst
+    }
+}
+
+// A bucket of bytes per second that can be shared between connections
+#[derive(Debug)]
+pub struct Throttle {
+    rate: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl Throttle {
+    // burst defaults to a second's worth
+    pub fn new(rate: u64, burst: Option<u64>) -> Throttle {
+        let burst = burst.unwrap_or(rate) as f64;
+        Throttle {
+            rate: rate as f64,
+            burst,
+            bucket: Mutex::new(Bucket::new(burst)),
+        }
+    }
+
+    // Account for sending n bytes and return how long to wait before sending
+    // them.
+    pub fn reserve(&self, n: usize) -> Duration {
+        self.bucket.lock().unwrap().reserve(self.rate, self.burst, n as f64)
+    }
+}
+
+#[derive(Debug)]
+pub struct Limiter {
+    max_entries: usize,
+    buckets: Mutex<HashMap<String, (Bucket, f64, f64)>>,
+}
+
+// The part of the client address a limit applies to. IPv6 clients are grouped
+// by prefix since they usually get a whole network.
+fn ip_key(ip: IpAddr, prefix6: u32) -> String {
+    match util::canonical_ip(ip) {
+        IpAddr::V6(v6) => {
+            let mask = u128::MAX.checked_shl(128 - prefix6.min(128)).unwrap_or(0);
+            let net = std::net::Ipv6Addr::from(u128::from(v6) & mask);
+            format!("{}/{}", net, prefix6)
+        }
+        v4 => v4.to_string(),
+    }
+}
+
+impl Limiter {
+    pub fn new(max_entries: Option<usize>) -> Limiter {
+        Limiter {
+            max_entries: max_entries.unwrap_or(DEFAULT_ENTRIES),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Check a request against limit. scope separates vhosts and routes, cert
+    // is the client certificate hash if there is one. Returns how long the
+    // client has to wait if it's over the limit.
+    pub fn check(
+        &self,
+        scope: &str,
+        limit: &config::RateLimit,
+        ip: Option<IpAddr>,
+        cert: Option<String>,
+    ) -> Result<(), Duration> {
+        // Clients with neither can't be told apart so aren't limited
+        let client = match (limit.key.as_deref(), cert, ip) {
+            (Some("cert"), Some(c), _) => c,
+            (_, _, Some(ip)) => ip_key(ip, limit.prefix6.unwrap_or(64)),
+            (_, _, None) => return Ok(()),
+        };
+        let key = format!("{} {}", scope, client);
+        let burst = limit.burst.unwrap_or(1.0).max(1.0);
+        let rate = limit.rate;
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if !buckets.contains_key(&key) && buckets.len() >= self.max_entries {
+            // Buckets that have refilled are the same as new ones
+            buckets.retain(|_, (b, r, bu)| !b.full(*r, *bu));
+            // Otherwise drop the least recently used eighth, so the next
+            // new clients don't each have to go through them all again
+            if buckets.len() >= self.max_entries {
+                let mut used: Vec<Instant> = buckets.values().map(|(b, _, _)| b.used).collect();
+                let n = (used.len() / 8).max(1);
+                let cutoff = *used.select_nth_unstable(n - 1).1;
+                buckets.retain(|_, (b, _, _)| b.used > cutoff);
+            }
+        }
+        let (bucket, _, _) = buckets
+            .entry(key)
+            .or_insert_with(|| (Bucket::new(burst), rate, burst));
+        bucket.take(rate, burst, 1.0)
+    }
+}
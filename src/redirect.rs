@@ -0,0 +1,81 @@
+use regex::Regex;
+use std::io;
+use url::Url;
+
+use crate::config;
+use crate::status::Status;
+
+#[derive(Debug, Clone)]
+pub struct Rule {
+    re: Regex,
+    to: String,
+    permanent: bool,
+    query: bool,
+}
+
+// Turn a glob into an anchored regex where each * and ? is a capture group.
+pub fn glob_to_regex(glob: &str) -> String {
+    let mut re = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => re.push_str("(.*)"),
+            '?' => re.push_str("(.)"),
+            _ => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    re
+}
+
+pub fn compile(rules: &[config::RedirectRule]) -> io::Result<Vec<Rule>> {
+    let mut compiled = Vec::new();
+    for r in rules {
+        let pattern = match (&r.regex, &r.glob) {
+            (Some(re), None) => re.clone(),
+            (None, Some(g)) => glob_to_regex(g),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("redirect to {} needs one of regex or glob", r.to),
+                ))
+            }
+        };
+        let re = Regex::new(&pattern)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        compiled.push(Rule {
+            re,
+            to: r.to.clone(),
+            permanent: r.permanent.unwrap_or(false),
+            query: r.query.unwrap_or(false),
+        });
+    }
+    Ok(compiled)
+}
+
+// Resolve a possibly relative redirect target against the request url
+pub fn resolve(url: &Url, target: &str, query: bool) -> Option<String> {
+    let mut u = url.join(target).ok()?;
+    if query && u.query().is_none() {
+        u.set_query(url.query());
+    }
+    Some(u.to_string())
+}
+
+// The status and absolute target of the first rule matching url's path
+pub fn find(rules: &[Rule], url: &Url) -> Option<(Status, String)> {
+    for r in rules {
+        let caps = match r.re.captures(url.path()) {
+            Some(c) => c,
+            None => continue,
+        };
+        let mut target = String::new();
+        caps.expand(&r.to, &mut target);
+        let stat = if r.permanent {
+            Status::RedirectPermanent
+        } else {
+            Status::RedirectTemporary
+        };
+        return resolve(url, &target, r.query).map(|t| (stat, t));
+    }
+    None
+}
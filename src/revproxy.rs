@@ -2,22 +2,40 @@
 use openssl::ssl::{SslConnector, SslMethod};
 use std::io;
 use std::net::ToSocketAddrs;
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 
 use crate::conn;
 use crate::logger;
+use crate::proxyproto;
 use crate::status::Status;
 
-pub async fn proxy(addr: String, u: url::Url, mut con: conn::Connection) -> Result<(), io::Error> {
+// A PROXY header of version for upstreams that want to know the client
+fn proxy_header(con: &conn::Connection, version: Option<u8>) -> Option<Vec<u8>> {
+    let h = proxyproto::Header {
+        src: con.peer.addr,
+        dst: con.peer.local_addr,
+        tlvs: Vec::new(),
+    };
+    version.map(|v| proxyproto::encode(v, &h))
+}
+
+pub async fn proxy(
+    addr: String,
+    u: url::Url,
+    con: &mut conn::Connection,
+    timeout: Duration,
+    header: Option<u8>,
+) -> Result<(), io::Error> {
     let p: Vec<&str> = u.path().trim_start_matches("/").splitn(2, "/").collect();
     if p.len() == 1 {
-        logger::logger(con.peer_addr, Status::NotFound, u.as_str());
+        logger::logger(con.peer.addr, Status::NotFound, u.as_str());
         con.send_status(Status::NotFound, None).await?;
         return Ok(());
     }
     if p[1] == "" || p[1] == "/" {
-        logger::logger(con.peer_addr, Status::NotFound, u.as_str());
+        logger::logger(con.peer.addr, Status::NotFound, u.as_str());
         con.send_status(Status::NotFound, None).await?;
         return Ok(());
     }
@@ -30,53 +48,75 @@ pub async fn proxy(addr: String, u: url::Url, mut con: conn::Connection) -> Resu
     connector.set_verify(openssl::ssl::SslVerifyMode::NONE);
     let config = connector.build().configure().unwrap();
 
-    let stream = match TcpStream::connect(&addr).await {
-        Ok(s) => s,
-        Err(_) => {
-            logger::logger(con.peer_addr, Status::ProxyError, u.as_str());
+    let header = proxy_header(con, header);
+    let upstream = async {
+        let mut stream = TcpStream::connect(&addr).await?;
+        if let Some(h) = header {
+            stream.write_all(&h).await?;
+        }
+        let mut stream = tokio_openssl::connect(config, "localhost", stream)
+            .await
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        stream.write_all(p[1].as_bytes()).await?;
+        stream.flush().await?;
+
+        let mut buf = vec![];
+        stream.read_to_end(&mut buf).await?;
+        Ok(buf) as io::Result<Vec<u8>>
+    };
+    let buf = match tokio::time::timeout(timeout, upstream).await {
+        Ok(Ok(b)) => b,
+        Ok(Err(_)) => {
+            logger::logger(con.peer.addr, Status::ProxyError, u.as_str());
             con.send_status(Status::ProxyError, None).await?;
             return Ok(());
         }
-    };
-    let mut stream = match tokio_openssl::connect(config, "localhost", stream).await {
-        Ok(s) => s,
         Err(_) => {
-            logger::logger(con.peer_addr, Status::ProxyError, u.as_str());
+            logger::timeout(con.peer.addr, "handler", u.as_str());
+            logger::logger(con.peer.addr, Status::ProxyError, u.as_str());
             con.send_status(Status::ProxyError, None).await?;
             return Ok(());
         }
     };
-    stream.write_all(p[1].as_bytes()).await?;
-    stream.flush().await?;
-
-    let mut buf = vec![];
-    stream.read_to_end(&mut buf).await?;
     // let req = String::from_utf8(buf[..].to_vec()).unwrap();
     con.send_raw(&buf).await?;
     Ok(())
 }
 
-pub async fn proxy_all(addr: &str, u: url::Url, mut con: conn::Connection) -> Result<(), io::Error> {
+pub async fn proxy_all(
+    addr: &str,
+    u: url::Url,
+    con: &mut conn::Connection,
+    timeout: Duration,
+    header: Option<u8>,
+) -> Result<(), io::Error> {
     let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
     connector.set_verify(openssl::ssl::SslVerifyMode::NONE);
     let config = connector.build().configure().unwrap();
 
-    // TCP handshake
-    let stream = match TcpStream::connect(&addr).await {
-        Ok(s) => s,
-        Err(_) => {
-            logger::logger(con.peer_addr, Status::ProxyError, u.as_str());
+    let domain = addr.splitn(2, ':').next().unwrap();
+    let header = proxy_header(con, header);
+    let upstream = async {
+        // TCP handshake
+        let mut stream = TcpStream::connect(&addr).await?;
+        if let Some(h) = header {
+            stream.write_all(&h).await?;
+        }
+        // TLS handshake with SNI
+        tokio_openssl::connect(config, domain, stream)
+            .await
+            .map_err(|e| io::Error::other(e.to_string()))
+    };
+    let mut stream = match tokio::time::timeout(timeout, upstream).await {
+        Ok(Ok(s)) => s,
+        Ok(Err(_)) => {
+            logger::logger(con.peer.addr, Status::ProxyError, u.as_str());
             con.send_status(Status::ProxyError, None).await?;
             return Ok(());
         }
-    };
-    let domain = addr.splitn(2, ':').next().unwrap();
-
-    // TLS handshake with SNI
-    let mut stream = match tokio_openssl::connect(config, domain, stream).await {
-        Ok(s) => s,
         Err(_) => {
-            logger::logger(con.peer_addr, Status::ProxyError, u.as_str());
+            logger::timeout(con.peer.addr, "handler", u.as_str());
+            logger::logger(con.peer.addr, Status::ProxyError, u.as_str());
             con.send_status(Status::ProxyError, None).await?;
             return Ok(());
         }
@@ -87,7 +127,12 @@ pub async fn proxy_all(addr: &str, u: url::Url, mut con: conn::Connection) -> Re
     stream.write_all(b"\r\n").await?;
     stream.flush().await?;
 
-    // stream to client
-    con.send_stream(&mut stream).await?;
-    Ok(())
+    // stream to client, giving up if the upstream stalls
+    match con.send_stream(&mut stream, timeout).await {
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+            logger::timeout(con.peer.addr, "handler", u.as_str());
+            Ok(())
+        }
+        r => r,
+    }
 }
@@ -0,0 +1,74 @@
+use regex::Regex;
+use std::io;
+use url::Url;
+
+use crate::config;
+use crate::redirect;
+
+#[derive(Debug, Clone)]
+pub struct Rule {
+    re: Regex,
+    query: Option<Regex>,
+    cert: Option<bool>,
+    to: String,
+}
+
+fn regex(pattern: &str) -> io::Result<Regex> {
+    Regex::new(pattern).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))
+}
+
+pub fn compile(rules: &[config::RewriteRule]) -> io::Result<Vec<Rule>> {
+    let mut compiled = Vec::new();
+    for r in rules {
+        let pattern = match (&r.regex, &r.glob) {
+            (Some(re), None) => re.clone(),
+            (None, Some(g)) => redirect::glob_to_regex(g),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("rewrite to {} needs one of regex or glob", r.to),
+                ))
+            }
+        };
+        compiled.push(Rule {
+            re: regex(&pattern)?,
+            query: match &r.query {
+                Some(q) => Some(regex(q)?),
+                None => None,
+            },
+            cert: r.cert,
+            to: r.to.clone(),
+        });
+    }
+    Ok(compiled)
+}
+
+// Apply the first rule whose conditions all match. The target replaces the
+// path, and the query too if it has one.
+pub fn rewrite(rules: &[Rule], url: &Url, cert: bool) -> Option<Url> {
+    for r in rules {
+        let caps = match r.re.captures(url.path()) {
+            Some(c) => c,
+            None => continue,
+        };
+        if let Some(q) = &r.query {
+            if !q.is_match(url.query().unwrap_or("")) {
+                continue;
+            }
+        }
+        if r.cert.is_some_and(|c| c != cert) {
+            continue;
+        }
+        let mut target = String::new();
+        caps.expand(&r.to, &mut target);
+        let mut u = url.clone();
+        let (path, query) = match target.split_once('?') {
+            Some((p, q)) => (p.to_string(), Some(q.to_string())),
+            None => (target, url.query().map(|q| q.to_string())),
+        };
+        u.set_path(&path);
+        u.set_query(query.as_deref());
+        return Some(u);
+    }
+    None
+}
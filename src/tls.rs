@@ -36,6 +36,9 @@ pub fn acceptor_conf(cfg: config::Config) -> Result<SslAcceptor, ErrorStack> {
         };
         let ctx = ctx.build();
         map.insert(server.hostname.clone(), ctx.clone());
+        for alias in server.alias.iter().flatten() {
+            map.insert(alias.clone(), ctx.clone());
+        }
         if num == 1 {
             map.insert("default".to_string(), ctx);
             num += 1;
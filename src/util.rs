@@ -1,10 +1,50 @@
-use url::form_urlencoded;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-pub fn url_decode(url: &[u8]) -> String {
-    let decoded: String = form_urlencoded::parse(url)
-        .map(|(key, val)| [key, val].concat())
-        .collect();
-    return decoded
+fn percent_decode(s: &str) -> String {
+    let b = s.as_bytes();
+    let mut out = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        let hex = b.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+        match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+            Some(c) if b[i] == b'%' => {
+                out.push(c);
+                i += 3;
+            }
+            _ => {
+                out.push(b[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// The path files are served from and path rules are matched against. It's
+// percent decoded with empty and dot segments removed, so there's only one
+// way to spell each path.
+pub fn canonical_path(path: &str) -> String {
+    let decoded = percent_decode(path);
+    let mut segments: Vec<&str> = Vec::new();
+    for s in decoded.split('/') {
+        match s {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            s => segments.push(s),
+        }
+    }
+    let mut out = format!("/{}", segments.join("/"));
+    if decoded.ends_with('/') && !segments.is_empty() {
+        out.push('/');
+    }
+    out
 }
 
 pub fn fingerhex(x509: &openssl::x509::X509) -> String {
@@ -18,3 +58,309 @@ pub fn fingerhex(x509: &openssl::x509::X509) -> String {
     }
     hex
 }
+
+// Returns the value whose key is the longest prefix of path.
+pub fn prefix_match<'a, T>(map: &'a HashMap<String, T>, path: &str) -> Option<&'a T> {
+    prefix_match_entry(map, path).map(|(_, v)| v)
+}
+
+pub fn prefix_match_entry<'a, T>(map: &'a HashMap<String, T>, path: &str) -> Option<(&'a String, &'a T)> {
+    map.iter()
+        .filter(|(k, _)| path.starts_with(k.as_str()))
+        .max_by_key(|(k, _)| k.len())
+}
+
+// Guess the charset of a text file from its first few kilobytes. Anything
+// that isn't valid UTF-8 is assumed to be Latin-1.
+pub fn detect_charset(path: &Path) -> io::Result<&'static str> {
+    let mut buf = [0; 4096];
+    let len = File::open(path)?.read(&mut buf)?;
+    match std::str::from_utf8(&buf[..len]) {
+        Ok(_) => Ok("utf-8"),
+        // A multibyte character cut off by the end of the buffer
+        Err(e) if e.error_len().is_none() => Ok("utf-8"),
+        Err(_) => Ok("iso-8859-1"),
+    }
+}
+
+// Match name against a shell style pattern supporting * and ?.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let n: Vec<char> = name.chars().collect();
+    let (mut pi, mut ni) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while ni < n.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == n[ni]) {
+            pi += 1;
+            ni += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ni));
+            pi += 1;
+        } else if let Some((sp, sn)) = star {
+            pi = sp + 1;
+            ni = sn + 1;
+            star = Some((sp, sn + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+// Split a time into UTC year, month, day, hour, minute and second
+pub fn civil(t: SystemTime) -> (i64, i64, i64, i64, i64, i64) {
+    let secs = match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(_) => 0,
+    };
+    let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    // Howard Hinnant's civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m, d, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+// Format a time as UTC "YYYY-MM-DD HH:MM:SS".
+pub fn fmt_time(t: SystemTime) -> String {
+    let (y, m, d, hh, mm, ss) = civil(t);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", y, m, d, hh, mm, ss)
+}
+
+// Format a byte count as e.g. 512, 1.5K, 20M
+pub fn fmt_size(len: u64) -> String {
+    let units = ["K", "M", "G", "T"];
+    if len < 1024 {
+        return len.to_string();
+    }
+    let mut size = len as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if size < 10.0 {
+        format!("{:.1}{}", size, units[unit])
+    } else {
+        format!("{:.0}{}", size, units[unit])
+    }
+}
+
+const MAGIC: &[(usize, &[u8], &str)] = &[
+    (0, b"\x89PNG\r\n\x1a\n", "image/png"),
+    (0, b"\xff\xd8\xff", "image/jpeg"),
+    (0, b"GIF87a", "image/gif"),
+    (0, b"GIF89a", "image/gif"),
+    (8, b"WEBP", "image/webp"),
+    (0, b"%PDF-", "application/pdf"),
+    (0, b"PK\x03\x04", "application/zip"),
+    (0, b"\x1f\x8b", "application/gzip"),
+    (0, b"BZh", "application/x-bzip2"),
+    (0, b"\xfd7zXZ\x00", "application/x-xz"),
+    (0, b"\x28\xb5\x2f\xfd", "application/zstd"),
+    (0, b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (257, b"ustar", "application/x-tar"),
+    (0, b"\x7fELF", "application/x-executable"),
+    (0, b"OggS", "audio/ogg"),
+    (0, b"fLaC", "audio/flac"),
+    (0, b"ID3", "audio/mpeg"),
+    (4, b"ftyp", "video/mp4"),
+    (0, b"\x1a\x45\xdf\xa3", "video/webm"),
+];
+
+// Guess a mime type from a file's first bytes. Files that look like text
+// are text/plain and anything else is application/octet-stream.
+pub fn sniff_mime(path: &Path) -> Option<&'static str> {
+    let mut buf = [0; 4096];
+    let len = File::open(path).ok()?.read(&mut buf).ok()?;
+    let buf = &buf[..len];
+    for (off, magic, mime) in MAGIC {
+        if buf.len() >= off + magic.len() && &buf[*off..off + magic.len()] == *magic {
+            return Some(mime);
+        }
+    }
+    // Legacy 8 bit text is fine too as long as there's no control characters
+    let control = |b: &u8| *b < 0x20 && !b"\t\n\r\x0c".contains(b) || *b == 0x7f;
+    if buf.iter().any(control) {
+        Some("application/octet-stream")
+    } else {
+        Some("text/plain")
+    }
+}
+
+// A primary language subtag with an optional region e.g. fr or pt-BR
+fn is_lang_tag(s: &str) -> bool {
+    let mut parts = s.splitn(2, '-');
+    let primary = parts.next().unwrap_or("");
+    if primary.len() != 2 || !primary.chars().all(|c| c.is_ascii_lowercase()) {
+        return false;
+    }
+    match parts.next() {
+        Some(r) => (2..=8).contains(&r.len()) && r.chars().all(|c| c.is_ascii_alphanumeric()),
+        None => true,
+    }
+}
+
+// Split a file name like "index.fr.gmi" into ("index", Some("fr"), "gmi")
+pub fn split_lang(name: &str) -> (&str, Option<&str>, &str) {
+    let (rest, ext) = match name.rfind('.') {
+        Some(i) => (&name[..i], &name[i + 1..]),
+        None => return (name, None, ""),
+    };
+    match rest.rfind('.') {
+        Some(i) if is_lang_tag(&rest[i + 1..]) => (&rest[..i], Some(&rest[i + 1..]), ext),
+        _ => (rest, None, ext),
+    }
+}
+
+// The other language versions of path in its directory as (lang, file name).
+// The version without a language tag has no lang.
+pub fn lang_variants(path: &Path) -> Vec<(Option<String>, String)> {
+    let name = match path.file_name().and_then(|n| n.to_str()) {
+        Some(n) => n,
+        None => return Vec::new(),
+    };
+    let (stem, _, ext) = split_lang(name);
+    let dir = match path.parent().map(std::fs::read_dir) {
+        Some(Ok(d)) => d,
+        _ => return Vec::new(),
+    };
+    let mut variants: Vec<(Option<String>, String)> = dir
+        .flatten()
+        .filter_map(|f| f.file_name().into_string().ok())
+        .filter(|n| n != name)
+        .filter_map(|n| {
+            let (s, lang, e) = split_lang(&n);
+            if s == stem && e == ext {
+                Some((lang.map(|l| l.to_string()), n.clone()))
+            } else {
+                None
+            }
+        })
+        .collect();
+    variants.sort();
+    variants
+}
+
+// A gemtext file name without its extension, for clean urls
+pub fn clean_name(name: &str) -> Option<String> {
+    name.strip_suffix(".gmi")
+        .or_else(|| name.strip_suffix(".gemini"))
+        .filter(|n| !n.is_empty())
+        .map(|n| n.to_string())
+}
+
+// Clients on a dual stack listener show up as ::ffff:a.b.c.d
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => ip,
+        },
+        _ => ip,
+    }
+}
+
+// Check if ip is in a range like 10.0.0.0/8, 2001:db8::/32 or a single address
+pub fn ip_in(ip: IpAddr, cidr: &str) -> bool {
+    let ip = canonical_ip(ip);
+    let (net, len) = match cidr.split_once('/') {
+        Some((n, l)) => (n, l.parse::<u32>().ok()),
+        None => (cidr, None),
+    };
+    let net = match net.parse::<IpAddr>() {
+        Ok(n) => n,
+        Err(_) => return false,
+    };
+    // A mapped range like ::ffff:10.0.0.0/104 is 10.0.0.0/8
+    let len = match (net, canonical_ip(net)) {
+        (IpAddr::V6(_), IpAddr::V4(_)) => len.map(|l| l.saturating_sub(96)),
+        _ => len,
+    };
+    let net = canonical_ip(net);
+    match (ip, net) {
+        (IpAddr::V4(a), IpAddr::V4(n)) => {
+            let len = len.unwrap_or(32).min(32);
+            let mask = u32::MAX.checked_shl(32 - len).unwrap_or(0);
+            u32::from(a) & mask == u32::from(n) & mask
+        }
+        (IpAddr::V6(a), IpAddr::V6(n)) => {
+            let len = len.unwrap_or(128).min(128);
+            let mask = u128::MAX.checked_shl(128 - len).unwrap_or(0);
+            u128::from(a) & mask == u128::from(n) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonical_paths() {
+        let cases = [
+            ("/", "/"),
+            ("", "/"),
+            ("/cgi-bin/admin/x", "/cgi-bin/admin/x"),
+            ("/cgi-bin/%61dmin/x", "/cgi-bin/admin/x"),
+            ("/cgi-bin//admin/x", "/cgi-bin/admin/x"),
+            ("//cgi-bin/./admin//", "/cgi-bin/admin/"),
+            ("/cgi-bin%2Fadmin/x", "/cgi-bin/admin/x"),
+            ("/a/../../b", "/b"),
+            ("/a/%2e%2e/b", "/b"),
+            ("/a%20b+c", "/a b+c"),
+            ("/100%/%zz", "/100%/%zz"),
+            ("/caf%C3%A9", "/café"),
+        ];
+        for (path, want) in cases.iter() {
+            assert_eq!(canonical_path(path), *want, "{}", path);
+        }
+    }
+
+    #[test]
+    fn prefix_matches() {
+        let mut map = HashMap::new();
+        map.insert("/".to_string(), 1);
+        map.insert("/cgi-bin/".to_string(), 2);
+        map.insert("/cgi-bin/admin/".to_string(), 3);
+        assert_eq!(prefix_match(&map, "/cgi-bin/admin/x"), Some(&3));
+        assert_eq!(prefix_match(&map, "/cgi-bin/adminx"), Some(&2));
+        assert_eq!(prefix_match(&map, "/index.gmi"), Some(&1));
+        assert_eq!(
+            prefix_match_entry(&map, "/cgi-bin/x").map(|(k, _)| k.as_str()),
+            Some("/cgi-bin/")
+        );
+        map.remove("/");
+        assert_eq!(prefix_match(&map, "/index.gmi"), None);
+    }
+
+    #[test]
+    fn ip_ranges() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(ip_in(ip("10.1.2.3"), "10.0.0.0/8"));
+        assert!(!ip_in(ip("11.1.2.3"), "10.0.0.0/8"));
+        assert!(ip_in(ip("192.0.2.7"), "192.0.2.7"));
+        assert!(!ip_in(ip("192.0.2.8"), "192.0.2.7"));
+        assert!(ip_in(ip("203.0.113.9"), "0.0.0.0/0"));
+        assert!(ip_in(ip("192.0.2.1"), "192.0.2.0/31"));
+        assert!(!ip_in(ip("192.0.2.2"), "192.0.2.0/31"));
+        assert!(ip_in(ip("2001:db8::1"), "2001:db8::/32"));
+        assert!(!ip_in(ip("2001:db9::1"), "2001:db8::/32"));
+        assert!(ip_in(ip("::1"), "::1"));
+        assert!(ip_in(ip("2001:db8::1"), "::/0"));
+        // Mapped addresses match their IPv4 ranges and the other way round
+        assert!(ip_in(ip("::ffff:10.1.2.3"), "10.0.0.0/8"));
+        assert!(ip_in(ip("10.1.2.3"), "::ffff:10.0.0.0/104"));
+        // IPv4 ranges don't match IPv6 clients and the other way round
+        assert!(!ip_in(ip("2001:db8::1"), "0.0.0.0/0"));
+        assert!(!ip_in(ip("10.1.2.3"), "::/0"));
+        assert!(!ip_in(ip("10.1.2.3"), "not an address"));
+    }
+}